@@ -0,0 +1,119 @@
+//! Cost of one call on the hot paths, run with `cargo bench`
+use aqueue::{AQueue, Actor};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+const CALLS: u64 = 1_000_000;
+
+/// counts the allocations made while measuring
+struct Counting;
+
+static ALLOCS: AtomicU64 = AtomicU64::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+struct Measure {
+    start: Instant,
+    allocs: u64,
+}
+
+impl Measure {
+    fn start() -> Measure {
+        Measure {
+            start: Instant::now(),
+            allocs: ALLOCS.load(Ordering::Relaxed),
+        }
+    }
+
+    fn report(self, name: &str) {
+        let nanos = self.start.elapsed().as_nanos() as f64 / CALLS as f64;
+        let allocs = (ALLOCS.load(Ordering::Relaxed) - self.allocs) as f64 / CALLS as f64;
+        println!("{:<22}{:>8.1} ns/call{:>6.1} allocs/call", name, nanos, allocs);
+    }
+}
+
+/// `CALLS` calls spread over `TASKS` tasks of a multi thread runtime
+fn contended(name: &str, queue: AQueue) {
+    const TASKS: u64 = 8;
+    let rt = tokio::runtime::Builder::new_multi_thread().build().unwrap();
+    let queue = Arc::new(queue);
+    let measure = Measure::start();
+    rt.block_on(async {
+        let tasks: Vec<_> = (0..TASKS)
+            .map(|_| {
+                let queue = queue.clone();
+                tokio::spawn(async move {
+                    for i in 0..CALLS / TASKS {
+                        queue.run(async move |x| Ok(x), i).await.unwrap();
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+    });
+    measure.report(name);
+}
+
+fn main() {
+    let rt = tokio::runtime::Builder::new_current_thread().build().unwrap();
+    rt.block_on(async {
+        let queue = AQueue::new();
+        let measure = Measure::start();
+        for i in 0..CALLS {
+            queue.run(async move |x| Ok(x), i).await.unwrap();
+        }
+        measure.report("AQueue::run");
+
+        let actor = Actor::new(0u64);
+        let measure = Measure::start();
+        for _ in 0..CALLS {
+            actor
+                .inner_call(async move |inner| {
+                    *inner.get_mut() += 1;
+                    Ok(())
+                })
+                .await
+                .unwrap();
+        }
+        measure.report("Actor::inner_call");
+
+        let queue = AQueue::builder().pool(64).build();
+        let measure = Measure::start();
+        for i in 0..CALLS {
+            queue.run(async move |x| Ok(x), i).await.unwrap();
+        }
+        measure.report("AQueue::run pooled");
+
+        let actor = Actor::with_queue(0u64, AQueue::builder().pool(64).build());
+        let measure = Measure::start();
+        for _ in 0..CALLS {
+            actor
+                .inner_call(async move |inner| {
+                    *inner.get_mut() += 1;
+                    Ok(())
+                })
+                .await
+                .unwrap();
+        }
+        measure.report("inner_call pooled");
+    });
+
+    contended("AQueue::run 8 tasks", AQueue::new());
+    contended("pooled 8 tasks", AQueue::builder().pool(64).build());
+}
@@ -1,4 +1,3 @@
-#![feature(async_closure)]
 use sqlx::{SqlitePool};
 use aqueue::Actor;
 use sqlx::sqlite::SqlitePoolOptions;
@@ -7,6 +6,7 @@ use async_trait ::async_trait;
 use std::env;
 use tokio::task::JoinHandle;
 
+#[allow(dead_code)]
 #[derive(sqlx::FromRow,Debug)]
 pub struct User { id: i64, name: String, gold:f64 }
 
@@ -38,7 +38,7 @@ impl DataBases{
         let row = sqlx::query(r#"
             insert into `user`(`id`,`name`,`gold`)
             values(?,?,?)
-         "#).bind(&self.auto_id)
+         "#).bind(self.auto_id)
             .bind(name)
             .bind(gold)
             .execute(&self.pool)
@@ -68,11 +68,9 @@ impl IDatabase for Actor<DataBases>{
     }
 
     async fn insert_user<'a>(&'a self, user: &'a str, gold: f64) -> Result<bool> {
-        unsafe {
-            self.inner_call_ref(async move |inner| {
-                inner.get_mut().insert_user(&user, gold).await
-            }).await
-        }
+        self.inner_call_ref(async move |inner| {
+            inner.get_mut().insert_user(user, gold).await
+        }).await
     }
 
     async fn select_all_users(&self) -> Result<Vec<User>> {
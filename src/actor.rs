@@ -1,14 +1,21 @@
-use crate::AQueue;
-use std::cell::UnsafeCell;
-use std::future::Future;
-use std::ops::Deref;
-use std::sync::Arc;
+use crate::{AQueue, AQueueError, CancelToken, IntoSharedQueue, Priority, QueueStats};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::future::Future;
+use core::ops::Deref;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll};
+#[cfg(feature = "std")]
+use core::time::Duration;
 use anyhow::Result;
 
 // Please do not use it at will
 pub struct InnerStore<T>(UnsafeCell<T>);
-unsafe impl<T> Sync for InnerStore<T> {}
-unsafe impl<T> Send for InnerStore<T> {}
+// the calls reaching the inner value are run one at a time by the queue, maybe on another thread
+unsafe impl<T: Send> Sync for InnerStore<T> {}
+unsafe impl<T: Send> Send for InnerStore<T> {}
 
 impl<T> InnerStore<T> {
     #[inline]
@@ -26,9 +33,47 @@ impl<T> InnerStore<T> {
     }
 }
 
-pub struct Actor<I> {
+/// `E` is the error type of the calls, see `AQueue`.
+/// `Actor::new` and `Actor::with_capacity` make an `anyhow` actor, use `Actor::with_queue` for another error type.
+pub struct Actor<I, E = anyhow::Error> {
     inner: Arc<InnerStore<I>>,
-    queue: AQueue,
+    queue: Arc<AQueue<E>>,
+    poisoned: Arc<AtomicBool>,
+}
+
+/// Sets the poisoned flag when the call panics, then lets the panic go on to the queue.
+/// Without `std` panics can't be caught, it only polls.
+struct WatchPanic<T> {
+    fut: T,
+    #[cfg_attr(not(feature = "std"), allow(dead_code))]
+    poisoned: Arc<AtomicBool>,
+}
+
+impl<T: Future> Future for WatchPanic<T> {
+    type Output = T::Output;
+
+    #[cfg(feature = "std")]
+    #[inline]
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        use std::panic::{catch_unwind, resume_unwind, AssertUnwindSafe};
+        // Safety: `fut` is never moved out of the pinned `WatchPanic`
+        let this = unsafe { self.get_unchecked_mut() };
+        let fut = unsafe { Pin::new_unchecked(&mut this.fut) };
+        match catch_unwind(AssertUnwindSafe(|| fut.poll(cx))) {
+            Ok(poll) => poll,
+            Err(payload) => {
+                this.poisoned.store(true, Ordering::Release);
+                resume_unwind(payload)
+            }
+        }
+    }
+
+    #[cfg(not(feature = "std"))]
+    #[inline]
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // Safety: `fut` is never moved out of the pinned `WatchPanic`
+        unsafe { self.map_unchecked_mut(|this| &mut this.fut) }.poll(cx)
+    }
 }
 
 pub struct RefInner<'a, T: ?Sized> {
@@ -43,21 +88,184 @@ impl<T: ?Sized> Deref for RefInner<'_, T> {
     }
 }
 
-impl<I: 'static> Actor<I> {
+impl<I: Send + 'static> Actor<I> {
     #[inline]
     pub fn new(x: I) -> Actor<I> {
+        Actor::with_queue(x, AQueue::new())
+    }
+
+    /// Create an actor whose call queue holds at most `cap` pending calls,
+    /// `inner_call` waits for a free slot when it is full.
+    #[inline]
+    pub fn with_capacity(x: I, cap: usize) -> Actor<I> {
+        Actor::with_queue(x, AQueue::with_capacity(cap))
+    }
+}
+
+impl<I, E> Actor<I, E>
+where
+    I: Send + 'static,
+    E: From<AQueueError> + Send + Sync + 'static,
+{
+    /// Create an actor on a queue configured by `AQueue::builder()`, or by `AQueueBuilder::<E>::default()`
+    /// for calls returning an error type of their own. Takes the `Arc` of `build_shared` as well.
+    #[inline]
+    pub fn with_queue(x: I, queue: impl IntoSharedQueue<E>) -> Actor<I, E> {
         Actor {
             inner: Arc::new(InnerStore::new(x)),
-            queue: AQueue::new(),
+            queue: queue.into_shared_queue(),
+            poisoned: Arc::new(AtomicBool::new(false)),
         }
     }
 
+    /// True once a call has panicked, the inner state may have been left half updated.
+    /// Calls still go through, it is up to the caller to check and repair.
     #[inline]
-    pub async fn inner_call<T, S>(&self, call: impl FnOnce(Arc<InnerStore<I>>) -> T ) -> Result<S>
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned.load(Ordering::Acquire)
+    }
+
+    #[inline]
+    pub fn clear_poison(&self) {
+        self.poisoned.store(false, Ordering::Release);
+    }
+
+    #[inline]
+    fn watch<T>(&self, fut: T) -> WatchPanic<T> {
+        WatchPanic { fut, poisoned: self.poisoned.clone() }
+    }
+
+    #[inline]
+    pub async fn inner_call<T, S>(&self, call: impl FnOnce(Arc<InnerStore<I>>) -> T ) -> Result<S, E>
+    where
+        T: Future<Output = Result<S, E>> + Send  + 'static,
+        S: 'static+Sync+Send, {
+        self.queue.run(|inner| self.watch(call(inner)), self.inner.clone()).await
+    }
+
+    /// Like `inner_call`, for synchronous threads, see `AQueue::run_blocking`
+    #[cfg(feature = "std")]
+    #[inline]
+    pub fn inner_call_blocking<T, S>(&self, call: impl FnOnce(Arc<InnerStore<I>>) -> T ) -> Result<S, E>
+    where
+        T: Future<Output = Result<S, E>> + Send  + 'static,
+        S: 'static+Sync+Send, {
+        self.queue.run_blocking(|inner| self.watch(call(inner)), self.inner.clone())
+    }
+
+    /// Like `inner_call`, but the call goes ahead of lower priority calls already queued
+    #[inline]
+    pub async fn inner_call_with_priority<T, S>(&self, priority: Priority, call: impl FnOnce(Arc<InnerStore<I>>) -> T ) -> Result<S, E>
     where
-        T: Future<Output = Result<S>> + Send  + 'static,
+        T: Future<Output = Result<S, E>> + Send  + 'static,
         S: 'static+Sync+Send, {
-        self.queue.run(call, self.inner.clone()).await
+        self.queue.run_with_priority(priority, |inner| self.watch(call(inner)), self.inner.clone()).await
+    }
+
+    /// Like `inner_call`, with a deadline to start (`wait`) and one to finish once started (`exec`),
+    /// see `AQueue::run_timeout`
+    #[cfg(feature = "std")]
+    #[inline]
+    pub async fn inner_call_timeout<T, S>(&self, wait: Option<Duration>, exec: Option<Duration>, call: impl FnOnce(Arc<InnerStore<I>>) -> T ) -> Result<S, E>
+    where
+        T: Future<Output = Result<S, E>> + Send  + 'static,
+        S: 'static+Sync+Send, {
+        self.queue.run_timeout(wait, exec, |inner| self.watch(call(inner)), self.inner.clone()).await
+    }
+
+    /// Like `inner_call`, but `call` also gets a `CancelToken`, see `AQueue::run_cancellable`
+    #[inline]
+    pub async fn inner_call_cancellable<T, S>(&self, call: impl FnOnce(Arc<InnerStore<I>>, CancelToken) -> T ) -> Result<S, E>
+    where
+        T: Future<Output = Result<S, E>> + Send  + 'static,
+        S: 'static+Sync+Send, {
+        self.queue.run_cancellable(|inner, token| self.watch(call(inner, token)), self.inner.clone()).await
+    }
+
+    /// Run all `calls` back to back with no other call in between, see `AQueue::run_batch`
+    #[inline]
+    pub async fn inner_call_batch<F, T, S>(&self, calls: impl IntoIterator<Item = F>) -> Result<Vec<Result<S, E>>, E>
+    where
+        F: FnOnce(Arc<InnerStore<I>>) -> T,
+        T: Future<Output = Result<S, E>> + Send  + 'static,
+        S: 'static+Sync+Send, {
+        let calls = calls.into_iter().map(|call| move |inner| self.watch(call(inner)));
+        self.queue.run_batch(calls, self.inner.clone()).await
+    }
+
+    /// Queue `call` without waiting for it, see `AQueue::post`
+    #[inline]
+    pub fn post<T, S>(&self, call: impl FnOnce(Arc<InnerStore<I>>) -> T ) -> Result<(), E>
+    where
+        T: Future<Output = Result<S, E>> + Send  + 'static,
+        S: 'static+Send, {
+        self.queue.post(|inner| self.watch(call(inner)), self.inner.clone())
+    }
+
+    /// Like `inner_call`, but fails at once with `AQueueError::Full`
+    /// instead of waiting when the bounded queue is full.
+    #[inline]
+    pub async fn try_inner_call<T, S>(&self, call: impl FnOnce(Arc<InnerStore<I>>) -> T ) -> Result<S, E>
+    where
+        T: Future<Output = Result<S, E>> + Send  + 'static,
+        S: 'static+Sync+Send, {
+        self.queue.try_run(|inner| self.watch(call(inner)), self.inner.clone()).await
+    }
+
+    /// Stop taking new calls, they fail with `AQueueError::Closed`. Queued calls still run.
+    #[inline]
+    pub fn close(&self) {
+        self.queue.close()
+    }
+
+    #[inline]
+    pub fn is_closed(&self) -> bool {
+        self.queue.is_closed()
+    }
+
+    /// Wait until every queued call has run, see `AQueue::drain`
+    #[inline]
+    pub async fn drain(&self) {
+        self.queue.drain().await
+    }
+
+    /// Hold the calls in the queue until `resume`, see `AQueue::pause`
+    #[inline]
+    pub fn pause(&self) {
+        self.queue.pause()
+    }
+
+    #[inline]
+    pub fn resume(&self) {
+        self.queue.resume()
+    }
+
+    #[inline]
+    pub fn is_paused(&self) -> bool {
+        self.queue.is_paused()
+    }
+
+    /// Calls queued but not started yet
+    #[inline]
+    pub fn pending(&self) -> usize {
+        self.queue.pending()
+    }
+
+    /// Counters of the actor's call queue, see `AQueue::stats`
+    #[inline]
+    pub fn stats(&self) -> QueueStats {
+        self.queue.stats()
+    }
+
+    /// Close, wait for the queued calls to finish and give back the inner value.
+    /// Fails if a call kept its `Arc<InnerStore<I>>` past its own end.
+    pub async fn shutdown(self) -> Result<I, E> {
+        self.close();
+        self.drain().await;
+        match Arc::try_unwrap(self.inner) {
+            Ok(inner) => Ok(inner.0.into_inner()),
+            Err(_) => Err(AQueueError::InnerShared.into()),
+        }
     }
 
     /// # Safety
@@ -66,14 +274,12 @@ impl<I: 'static> Actor<I> {
         RefInner { value: self.inner.get() }
     }
 
-    /// # Safety
-    ///
-    /// 捕获闭包的借用参数，可能会导致问题，请勿乱用
+    /// Like `inner_call`, but the call may borrow from the caller, see `AQueue::ref_run`
     #[inline]
-    pub async unsafe fn inner_call_ref<'a,T,S>(&'a self, call: impl FnOnce(Arc<InnerStore<I>>) -> T ) -> Result<S>
+    pub async fn inner_call_ref<'a,T,S>(&'a self, call: impl FnOnce(Arc<InnerStore<I>>) -> T ) -> Result<S, E>
         where
-            T: Future<Output = Result<S>> + Send  + 'a,
+            T: Future<Output = Result<S, E>> + Send  + 'a,
             S: 'static+Sync+Send, {
-        self.queue.ref_run(call, self.inner.clone()).await
+        self.queue.ref_run(|inner| self.watch(call(inner)), self.inner.clone()).await
     }
 }
@@ -0,0 +1,60 @@
+use alloc::string::String;
+use core::fmt;
+
+/// Errors raised by the queue itself, as opposed to the ones returned by queued calls.
+/// They are carried inside `anyhow::Error`, use `err.downcast_ref::<AQueueError>()` to tell them apart.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AQueueError {
+    /// the bounded queue has no free slot
+    Full,
+    /// the queue was closed, it takes no new items
+    Closed,
+    /// the item did not start before its wait deadline, it was dropped without running
+    WaitTimeout,
+    /// the item started but did not finish before its execution deadline, it was dropped mid-way
+    ExecTimeout,
+    /// the item panicked, carries the panic message; the queue went on with the next item
+    Panicked(String),
+    /// the caller stopped waiting for the result, it could not be delivered
+    ReceiverDropped,
+    /// the item was dropped without sending a result
+    SenderDropped,
+    /// the item was run a second time, there is nothing left to run
+    AlreadyRun,
+    /// the call returned an error, which went to its caller; only `QueueItem::run` reports this
+    Failed,
+    /// a blocking call was made where it would block an async context, see `AQueue::run_blocking`
+    BlockingInAsync,
+    /// `Actor::shutdown` could not take the inner value back, something still holds it
+    InnerShared,
+}
+
+impl fmt::Display for AQueueError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AQueueError::Full => write!(f, "queue is full"),
+            AQueueError::Closed => write!(f, "queue is closed"),
+            AQueueError::WaitTimeout => write!(f, "timed out waiting in queue"),
+            AQueueError::ExecTimeout => write!(f, "timed out running"),
+            AQueueError::Panicked(msg) => write!(f, "panicked: {}", msg),
+            AQueueError::ReceiverDropped => write!(f, "result receiver dropped"),
+            AQueueError::SenderDropped => write!(f, "result sender dropped"),
+            AQueueError::AlreadyRun => write!(f, "item already run"),
+            AQueueError::Failed => write!(f, "call failed"),
+            AQueueError::BlockingInAsync => write!(f, "blocking call inside an async context"),
+            AQueueError::InnerShared => write!(f, "actor inner is still shared"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for AQueueError {}
+
+// without `std` anyhow only takes errors through `Error::msg`
+#[cfg(not(feature = "std"))]
+impl From<AQueueError> for anyhow::Error {
+    #[inline]
+    fn from(err: AQueueError) -> anyhow::Error {
+        anyhow::Error::msg(err)
+    }
+}
@@ -1,8 +1,16 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
 pub mod actor;
+pub mod error;
 pub mod queue;
+pub mod runtime;
 
 pub use actor::Actor;
-pub use queue::{AQueue, AQueueItem, QueueItem};
+pub use error::AQueueError;
+pub use runtime::{Spawner, Timer};
+pub use queue::{AQueue, AQueueBuilder, AQueueItem, CancelToken, Histogram, IntoSharedQueue, Priority, QueueItem, QueueStats, Scheduling};
 
 
 
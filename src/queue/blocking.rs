@@ -0,0 +1,69 @@
+use std::cell::Cell;
+use std::future::Future;
+use std::pin::pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+
+thread_local! {
+    // > 0 while this thread polls a queue item or waits in a blocking call
+    static IN_ASYNC: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Marks the thread as inside an async context until dropped
+pub(crate) struct Enter(());
+
+impl Drop for Enter {
+    #[inline]
+    fn drop(&mut self) {
+        IN_ASYNC.with(|depth| depth.set(depth.get() - 1));
+    }
+}
+
+#[inline]
+pub(crate) fn enter() -> Enter {
+    IN_ASYNC.with(|depth| depth.set(depth.get() + 1));
+    Enter(())
+}
+
+/// True if blocking this thread would block an async context:
+/// inside a queue item, another blocking call, or a tokio runtime with the `tokio` feature
+#[inline]
+pub(crate) fn in_async() -> bool {
+    if IN_ASYNC.with(|depth| depth.get() > 0) {
+        return true;
+    }
+    #[cfg(feature = "tokio")]
+    if tokio::runtime::Handle::try_current().is_ok() {
+        return true;
+    }
+    false
+}
+
+struct Unpark(Thread);
+
+impl Wake for Unpark {
+    #[inline]
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+
+    #[inline]
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Poll `fut` on this thread, parking it while there is nothing to do
+pub(crate) fn block_on<F: Future>(fut: F) -> F::Output {
+    let _enter = enter();
+    let waker = Waker::from(Arc::new(Unpark(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    let mut fut = pin!(fut);
+    loop {
+        if let Poll::Ready(r) = fut.as_mut().poll(&mut cx) {
+            return r;
+        }
+        thread::park();
+    }
+}
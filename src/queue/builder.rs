@@ -0,0 +1,189 @@
+use super::{AQueue, ErrorSink, Scheduling};
+use crate::{AQueueError, Spawner, Timer};
+use alloc::sync::Arc;
+use core::time::Duration;
+
+/// Configure an `AQueue` before creating it
+///
+/// ```
+/// use aqueue::{AQueue, Scheduling};
+/// let queue = AQueue::builder().capacity(1024).scheduling(Scheduling::Aging(16)).build();
+/// assert_eq!(queue.capacity(), Some(1024));
+/// ```
+///
+/// `AQueue::builder()` makes an `anyhow` queue, start from `AQueueBuilder::<E>::default()` for another error type.
+pub struct AQueueBuilder<E = anyhow::Error> {
+    pub(super) capacity: Option<usize>,
+    pub(super) scheduling: Scheduling,
+    pub(super) spawner: Option<Arc<dyn Spawner>>,
+    pub(super) timer: Option<Arc<dyn Timer>>,
+    pub(super) error_sink: Option<ErrorSink<E>>,
+    pub(super) stats_sample: u32,
+    pub(super) pool: usize,
+    pub(super) drain_budget: Option<usize>,
+    pub(super) drain_time: Option<Duration>,
+}
+
+impl<E> Clone for AQueueBuilder<E> {
+    #[inline]
+    fn clone(&self) -> Self {
+        AQueueBuilder {
+            capacity: self.capacity,
+            scheduling: self.scheduling,
+            spawner: self.spawner.clone(),
+            timer: self.timer.clone(),
+            error_sink: self.error_sink.clone(),
+            stats_sample: self.stats_sample,
+            pool: self.pool,
+            drain_budget: self.drain_budget,
+            drain_time: self.drain_time,
+        }
+    }
+}
+
+impl<E> Default for AQueueBuilder<E> {
+    #[inline]
+    fn default() -> Self {
+        AQueueBuilder {
+            capacity: None,
+            scheduling: Scheduling::default(),
+            spawner: None,
+            timer: None,
+            error_sink: None,
+            stats_sample: 16,
+            pool: 0,
+            drain_budget: None,
+            drain_time: None,
+        }
+    }
+}
+
+impl<E> AQueueBuilder<E>
+where
+    E: From<AQueueError> + Send + Sync + 'static,
+{
+    /// Hold at most `cap` pending items, pushes wait for a free slot when full
+    ///
+    /// # Panics
+    ///
+    /// If `cap` is zero.
+    #[inline]
+    pub fn capacity(mut self, cap: usize) -> Self {
+        assert!(cap > 0, "capacity must be positive");
+        self.capacity = Some(cap);
+        self
+    }
+
+    /// How priority lanes are popped, `Scheduling::Strict` by default
+    ///
+    /// # Panics
+    ///
+    /// If it is `Scheduling::Aging(0)`.
+    #[inline]
+    pub fn scheduling(mut self, scheduling: Scheduling) -> Self {
+        assert!(scheduling != Scheduling::Aging(0), "aging limit must be positive");
+        self.scheduling = scheduling;
+        self
+    }
+
+    /// Used to start running the queue when `post` finds nobody doing it,
+    /// and, on a queue shared by `build_shared` (or `AQueue::into_shared`, `Actor::with_queue`),
+    /// when whoever ran it is dropped with items left. Without it posted items wait for the next caller
+    #[inline]
+    pub fn spawner(mut self, spawner: impl Spawner + 'static) -> Self {
+        self.spawner = Some(Arc::new(spawner));
+        self
+    }
+
+    /// Used for the `run_timeout` deadlines instead of `futures-timer`
+    #[inline]
+    pub fn timer(mut self, timer: impl Timer + 'static) -> Self {
+        self.timer = Some(Arc::new(timer));
+        self
+    }
+
+    /// Use `rt` both as the spawner and the timer, e.g. `runtime(aqueue::runtime::Tokio::current())`
+    #[inline]
+    pub fn runtime<R>(self, rt: R) -> Self
+    where
+        R: Spawner + Timer + Clone + 'static,
+    {
+        self.spawner(rt.clone()).timer(rt)
+    }
+
+    /// Receives the errors of posted items, they are dropped otherwise
+    #[inline]
+    pub fn error_sink(mut self, sink: impl Fn(E) + Send + Sync + 'static) -> Self {
+        self.error_sink = Some(Arc::new(sink));
+        self
+    }
+
+    /// Time one item in `n` for the wait and run histograms of `AQueue::stats`, 16 by default.
+    /// 1 times every item, at the cost of a few clock reads each. The counters are always exact.
+    ///
+    /// # Panics
+    ///
+    /// If `n` is zero.
+    #[inline]
+    pub fn stats_sample(mut self, n: u32) -> Self {
+        assert!(n > 0, "stats sample must be positive");
+        self.stats_sample = n;
+        self
+    }
+
+    /// Keep up to `size` freed item blocks for reuse, so hot queues stop going to the allocator:
+    /// each `run` (and friends) call puts its item and result slot in one block of 256 bytes,
+    /// taken from the pool when it has one. Calls too big for a block are allocated as usual.
+    /// Off (0) by default, hits and misses show in `AQueue::stats`.
+    #[inline]
+    pub fn pool(mut self, size: usize) -> Self {
+        self.pool = size;
+        self
+    }
+
+    /// Let whoever runs the queue start at most `items` items in a row,
+    /// then hand over to a waiting caller and yield to the runtime. Unlimited by default.
+    ///
+    /// # Panics
+    ///
+    /// If `items` is zero.
+    #[inline]
+    pub fn drain_budget(mut self, items: usize) -> Self {
+        assert!(items > 0, "drain budget must be positive");
+        self.drain_budget = Some(items);
+        self
+    }
+
+    /// Like `drain_budget`, but bounded by time: no new item is started once `limit` has passed.
+    /// The item running when it runs out still finishes.
+    #[cfg(feature = "std")]
+    #[inline]
+    pub fn drain_time(mut self, limit: Duration) -> Self {
+        self.drain_time = Some(limit);
+        self
+    }
+
+    #[inline]
+    pub fn build(self) -> AQueue<E> {
+        AQueue::from_builder(self)
+    }
+
+    /// Build the queue behind an `Arc`, see `AQueue::into_shared`
+    #[inline]
+    pub fn build_shared(self) -> Arc<AQueue<E>> {
+        self.build().into_shared()
+    }
+
+    /// Build a queue run on its own task, started on `spawner` whenever items come in and nobody runs it.
+    /// Callers then only wait for their own result instead of running the other callers' items.
+    /// `spawner` is also the one used by `post`.
+    #[inline]
+    pub fn build_driven(mut self, spawner: impl Spawner + 'static) -> Arc<AQueue<E>> {
+        self.spawner = Some(Arc::new(spawner));
+        Arc::new_cyclic(|this| AQueue {
+            this: Some(this.clone()),
+            driven: true,
+            ..AQueue::from_builder(self)
+        })
+    }
+}
@@ -1,52 +1,483 @@
-use super::QueueItem;
-use async_trait::async_trait;
+use super::panic::poll_catch;
+use super::trace::ItemSpan;
+use super::{ErrorSink, QueueItem};
 use async_oneshot::{oneshot, Receiver, Sender};
-use std::cell::RefCell;
-use std::future::Future;
-use anyhow::*;
-use std::pin::Pin;
+use super::time::{self, Instant};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::future::Future;
+use anyhow::Result;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU8, Ordering};
+use core::task::{Context, Poll};
+use crate::AQueueError;
+use event_listener::{Event, EventListener};
 
-pub type BoxFuture<'a,S>=Pin<Box<dyn Future<Output = Result<S>> + Send+'a>>;
+const WAITING: u8 = 0;
+const RUNNING: u8 = 1;
+const CANCELLED: u8 = 2;
 
-pub struct AQueueItem<'a,S> {
-    call: RefCell<Option<BoxFuture<'a,S>>>,
-    result_sender: RefCell<Option<Sender<Result<S>>>>,
+struct GateState {
+    state: AtomicU8,
+    cancelled: Event,
 }
 
-unsafe impl<'a,S> Send for AQueueItem<'a,S> {}
-unsafe impl<'a,S> Sync for AQueueItem<'a,S> {}
+/// Decides between the running side starting an item and its caller giving up on it,
+/// whichever comes first wins
+#[derive(Clone)]
+pub(crate) struct StartGate {
+    inner: Arc<GateState>,
+    deadline: Option<Instant>,
+}
+
+impl StartGate {
+    #[inline]
+    pub fn new(deadline: Option<Instant>) -> StartGate {
+        StartGate {
+            inner: Arc::new(GateState {
+                state: AtomicU8::new(WAITING),
+                cancelled: Event::new(),
+            }),
+            deadline,
+        }
+    }
+
+    /// caller side, true if the item had not started and now never will
+    #[inline]
+    pub fn cancel(&self) -> bool {
+        self.inner.state.compare_exchange(WAITING, CANCELLED, Ordering::AcqRel, Ordering::Acquire).is_ok()
+    }
+
+    /// caller side, the caller is gone: the item must not start, and if it runs it should stop
+    #[inline]
+    fn abort(&self) {
+        self.inner.state.store(CANCELLED, Ordering::Release);
+        self.inner.cancelled.notify(usize::MAX);
+    }
+
+    #[inline]
+    fn is_cancelled(&self) -> bool {
+        self.inner.state.load(Ordering::Acquire) == CANCELLED
+    }
+
+    /// running side, true if the item may start
+    #[inline]
+    fn start(&self) -> bool {
+        if matches!(self.deadline, Some(deadline) if time::passed(deadline)) {
+            self.cancel();
+        }
+        self.inner.state.compare_exchange(WAITING, RUNNING, Ordering::AcqRel, Ordering::Acquire).is_ok()
+    }
+}
+
+/// Aborts the item when the caller's future is dropped before it got the result
+pub(crate) struct AbortOnDrop(Option<StartGate>);
+
+impl AbortOnDrop {
+    #[inline]
+    pub fn new(gate: StartGate) -> AbortOnDrop {
+        AbortOnDrop(Some(gate))
+    }
+
+    /// the caller got its result
+    #[inline]
+    pub fn disarm(mut self) {
+        self.0 = None;
+    }
+}
+
+impl Drop for AbortOnDrop {
+    #[inline]
+    fn drop(&mut self) {
+        if let Some(gate) = self.0.take() {
+            gate.abort();
+        }
+    }
+}
+
+/// Passed to calls made with `run_cancellable`,
+/// it reports when the caller has dropped the call while the item is running
+#[derive(Clone)]
+pub struct CancelToken(StartGate);
 
-#[async_trait]
-impl<'a,S> QueueItem for AQueueItem<'a,S>
+impl CancelToken {
+    #[inline]
+    pub(crate) fn new(gate: StartGate) -> CancelToken {
+        CancelToken(gate)
+    }
+
+    #[inline]
+    pub fn is_cancelled(&self) -> bool {
+        self.0.is_cancelled()
+    }
+
+    /// resolves once the caller is gone
+    pub async fn cancelled(&self) {
+        loop {
+            if self.is_cancelled() {
+                return;
+            }
+            let listener = self.0.inner.cancelled.listen();
+            if self.is_cancelled() {
+                return;
+            }
+            listener.await;
+        }
+    }
+}
+
+// the turn's item is still queued
+const QUEUED: u8 = 0;
+// the item came up and holds the queue for the caller
+const GRANTED: u8 = 1;
+// the caller is done with its turn, or gone
+const DONE: u8 = 2;
+
+struct TurnState {
+    state: AtomicU8,
+    granted: Event,
+    done: Event,
+}
+
+/// The caller's end of a turn: once granted, the queue runs nothing else until this is dropped
+pub(crate) struct Turn(Arc<TurnState>);
+
+impl Turn {
+    /// the turn, and the item to queue for it
+    #[inline]
+    pub fn new() -> (Turn, TurnItem) {
+        let state = Arc::new(TurnState {
+            state: AtomicU8::new(QUEUED),
+            granted: Event::new(),
+            done: Event::new(),
+        });
+        (Turn(state.clone()), TurnItem { state, done: None })
+    }
+
+    #[inline]
+    pub fn is_granted(&self) -> bool {
+        self.0.state.load(Ordering::Acquire) == GRANTED
+    }
+
+    #[inline]
+    pub fn listen(&self) -> EventListener {
+        self.0.granted.listen()
+    }
+}
+
+impl Drop for Turn {
+    #[inline]
+    fn drop(&mut self) {
+        self.0.state.store(DONE, Ordering::Release);
+        self.0.done.notify(usize::MAX);
+    }
+}
+
+/// Holds the queue's place for a turn, when it comes up it grants the turn
+/// and stays pending until the caller is done with it
+pub(crate) struct TurnItem {
+    state: Arc<TurnState>,
+    done: Option<EventListener>,
+}
+
+impl<E> QueueItem<E> for TurnItem {
+    #[inline]
+    fn poll_run(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), E>> {
+        let this = self.get_mut();
+        let turn = &this.state;
+        // a caller gone before its turn came up leaves DONE, there is nothing to grant then
+        if turn.state.compare_exchange(QUEUED, GRANTED, Ordering::AcqRel, Ordering::Acquire).is_ok() {
+            turn.granted.notify(usize::MAX);
+        }
+        loop {
+            if turn.state.load(Ordering::Acquire) == DONE {
+                return Poll::Ready(Ok(()));
+            }
+            match this.done {
+                Some(ref mut listener) => match Pin::new(listener).poll(cx) {
+                    Poll::Ready(()) => this.done = None,
+                    Poll::Pending => return Poll::Pending,
+                },
+                // check again once listening, the caller may be done meanwhile
+                None => this.done = Some(turn.done.listen()),
+            }
+        }
+    }
+}
+
+/// Where an item sends its result
+pub(crate) trait ResultTx<T> {
+    /// the caller is gone, nobody wants the result
+    fn is_closed(&self) -> bool;
+    /// false if the caller is gone
+    fn send(&mut self, value: T) -> bool;
+}
+
+impl<T> ResultTx<T> for Sender<T> {
+    #[inline]
+    fn is_closed(&self) -> bool {
+        Sender::is_closed(self)
+    }
+
+    #[inline]
+    fn send(&mut self, value: T) -> bool {
+        Sender::send(self, value).is_ok()
+    }
+}
+
+/// Runs the call `F` and sends its result through `Tx`
+pub(crate) struct Call<F, Tx> {
+    call: Option<F>,
+    result_sender: Option<Tx>,
+    gate: Option<StartGate>,
+    span: ItemSpan,
+    started: bool,
+}
+
+impl<F, Tx> Call<F, Tx> {
+    #[inline]
+    pub fn new(call: F, result_sender: Tx, gate: Option<StartGate>) -> Call<F, Tx> {
+        Call {
+            call: Some(call),
+            result_sender: Some(result_sender),
+            gate,
+            span: ItemSpan::current(),
+            started: false,
+        }
+    }
+}
+
+impl<F, Tx, S, E> QueueItem<E> for Call<F, Tx>
 where
-    S: 'static+Sync+Send
+    F: Future<Output = Result<S, E>>,
+    Tx: ResultTx<Result<S, E>>,
+    E: From<AQueueError>,
 {
     #[inline]
-    async fn run(&self) -> Result<()> {
-        let mut sender = self.result_sender.take().ok_or_else(|| anyhow!("not call one_shot is none"))?;
-        sender.send( self.run().await).map_err(|_|anyhow!("rx is close"))
+    fn poll_run(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), E>> {
+        // Safety: `call` is never moved out, only dropped in place
+        let this = unsafe { self.get_unchecked_mut() };
+        let sender = match this.result_sender {
+            Some(ref mut sender) => sender,
+            None => return Poll::Ready(Err(AQueueError::AlreadyRun.into())),
+        };
+        if !this.started {
+            if sender.is_closed() {
+                // the caller is gone, nobody wants the result
+                this.call = None;
+                this.result_sender = None;
+                return Poll::Ready(Err(AQueueError::ReceiverDropped.into()));
+            }
+            if let Some(ref gate) = this.gate {
+                if !gate.start() {
+                    // the caller gave up waiting, drop the call without running it
+                    this.call = None;
+                    if let Some(mut sender) = this.result_sender.take() {
+                        sender.send(Err(AQueueError::WaitTimeout.into()));
+                    }
+                    return Poll::Ready(Err(AQueueError::WaitTimeout.into()));
+                }
+            }
+            this.started = true;
+            this.span.started();
+        }
+        let r = match this.call.as_mut() {
+            Some(call) => {
+                let call = unsafe { Pin::new_unchecked(call) };
+                match this.span.in_scope(|| poll_catch(call, cx)) {
+                    Poll::Ready(r) => r,
+                    Poll::Pending => return Poll::Pending,
+                }
+            }
+            None => Err(AQueueError::AlreadyRun.into()),
+        };
+        this.call = None;
+        this.span.finished(r.is_ok());
+        let outcome = outcome(&r);
+        let mut sender = match this.result_sender.take() {
+            Some(sender) => sender,
+            None => return Poll::Ready(Err(AQueueError::AlreadyRun.into())),
+        };
+        let sent = sender.send(r);
+        match this.gate {
+            // cancelled while running, the caller is gone on purpose
+            Some(ref gate) if gate.is_cancelled() => {}
+            _ if !sent => return Poll::Ready(Err(AQueueError::ReceiverDropped.into())),
+            _ => {}
+        }
+        Poll::Ready(outcome)
     }
 }
 
-impl<'a,S> AQueueItem<'a,S>
+/// An item to `AQueue::push`: runs the call `F` and sends its result to the receiver made with it.
+/// The call is stored inline, the item is the only allocation besides the result channel.
+pub struct AQueueItem<F, S, E = anyhow::Error>(Call<F, Sender<Result<S, E>>>);
+
+impl<F, S, E> QueueItem<E> for AQueueItem<F, S, E>
 where
-    S: 'static+Sync+Send
+    F: Future<Output = Result<S, E>>,
+    S: 'static+Sync+Send,
+    E: From<AQueueError> + Send + Sync + 'static,
 {
     #[inline]
-    pub fn new(call: BoxFuture<'a,S>) -> (Receiver<Result<S>>, Self) {
+    fn poll_run(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), E>> {
+        // Safety: plain projection, the inner item is never moved out
+        unsafe { self.map_unchecked_mut(|item| &mut item.0) }.poll_run(cx)
+    }
+}
+
+impl<F, S, E> AQueueItem<F, S, E>
+where
+    F: Future<Output = Result<S, E>>,
+    S: 'static+Sync+Send,
+    E: From<AQueueError> + Send + Sync + 'static,
+{
+    #[inline]
+    pub fn new(call: F) -> (Receiver<Result<S, E>>, Self) {
         let (tx, rx) = oneshot();
+        (rx, AQueueItem(Call::new(call, tx, None)))
+    }
+}
+
+/// Item queued by `post`, nobody waits for it so errors go to the error sink
+pub(crate) struct PostItem<F, E> {
+    call: Option<F>,
+    error_sink: Option<ErrorSink<E>>,
+    span: ItemSpan,
+    started: bool,
+}
+
+impl<F, S, E> QueueItem<E> for PostItem<F, E>
+where
+    F: Future<Output = Result<S, E>>,
+    S: 'static+Send,
+    E: From<AQueueError> + Send + Sync + 'static,
+{
+    #[inline]
+    fn poll_run(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), E>> {
+        // Safety: `call` is never moved out, only dropped in place
+        let this = unsafe { self.get_unchecked_mut() };
+        let call = match this.call.as_mut() {
+            Some(call) => unsafe { Pin::new_unchecked(call) },
+            None => return Poll::Ready(Err(AQueueError::AlreadyRun.into())),
+        };
+        if !this.started {
+            this.started = true;
+            this.span.started();
+        }
+        let r = match this.span.in_scope(|| poll_catch(call, cx)) {
+            Poll::Ready(r) => r,
+            Poll::Pending => return Poll::Pending,
+        };
+        this.call = None;
+        this.span.finished(r.is_ok());
+        let outcome = outcome(&r);
+        if let Err(err) = r {
+            if let Some(ref sink) = this.error_sink {
+                sink(err);
+            }
+        }
+        Poll::Ready(outcome)
+    }
+}
+
+impl<F, E> PostItem<F, E> {
+    #[inline]
+    pub fn new(call: F, error_sink: Option<ErrorSink<E>>) -> Self {
+        PostItem {
+            call: Some(call),
+            error_sink,
+            span: ItemSpan::current(),
+            started: false,
+        }
+    }
+}
+
+/// what `QueueItem::poll_run` reports for a call whose result went to someone else
+#[inline]
+fn outcome<S, E: From<AQueueError>>(r: &Result<S, E>) -> Result<(), E> {
+    match r {
+        Ok(_) => Ok(()),
+        Err(_) => Err(AQueueError::Failed.into()),
+    }
+}
+
+type BatchResult<S, E> = Result<Vec<Result<S, E>>, E>;
+
+/// Several calls queued as one item, so they run back to back with nothing in between
+pub(crate) struct BatchItem<T, S, E> {
+    // each call is dropped in place once done, the vector itself never moves them
+    calls: Vec<Option<T>>,
+    results: Vec<Result<S, E>>,
+    result_sender: Option<Sender<BatchResult<S, E>>>,
+    span: ItemSpan,
+    started: bool,
+}
+
+impl<T, S, E> QueueItem<E> for BatchItem<T, S, E>
+where
+    T: Future<Output = Result<S, E>> + Send,
+    S: 'static+Sync+Send,
+    E: From<AQueueError> + Send + Sync + 'static,
+{
+    #[inline]
+    fn poll_run(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), E>> {
+        // Safety: the calls are never moved out of their slots, only dropped in place
+        let this = unsafe { self.get_unchecked_mut() };
+        let sender = match this.result_sender {
+            Some(ref mut sender) => sender,
+            None => return Poll::Ready(Err(AQueueError::AlreadyRun.into())),
+        };
+        if !this.started {
+            if sender.is_closed() {
+                this.calls.clear();
+                this.result_sender = None;
+                return Poll::Ready(Err(AQueueError::ReceiverDropped.into()));
+            }
+            this.started = true;
+            this.span.started();
+        }
+        while let Some(slot) = this.calls.get_mut(this.results.len()) {
+            let call = match slot.as_mut() {
+                Some(call) => unsafe { Pin::new_unchecked(call) },
+                None => return Poll::Ready(Err(AQueueError::AlreadyRun.into())),
+            };
+            let r = match this.span.in_scope(|| poll_catch(call, cx)) {
+                Poll::Ready(r) => r,
+                Poll::Pending => return Poll::Pending,
+            };
+            *slot = None;
+            this.results.push(r);
+        }
+        this.calls.clear();
+        this.span.finished(true);
+        let results = core::mem::take(&mut this.results);
+        match this.result_sender.take() {
+            Some(mut sender) => Poll::Ready(sender.send(Ok(results)).map_err(|_| AQueueError::ReceiverDropped.into())),
+            None => Poll::Ready(Err(AQueueError::AlreadyRun.into())),
+        }
+    }
+}
+
+impl<T, S, E> BatchItem<T, S, E>
+where
+    T: Future<Output = Result<S, E>> + Send,
+    S: 'static+Sync+Send,
+    E: From<AQueueError> + Send + Sync + 'static,
+{
+    #[inline]
+    pub fn new(calls: Vec<T>) -> (Receiver<BatchResult<S, E>>, Self) {
+        let (tx, rx) = oneshot();
+        let results = Vec::with_capacity(calls.len());
         (
             rx,
-            AQueueItem {
-                call: RefCell::new(Some(call)),
-                result_sender:RefCell::new( Some(tx))
+            BatchItem {
+                calls: calls.into_iter().map(Some).collect(),
+                results,
+                result_sender: Some(tx),
+                span: ItemSpan::current(),
+                started: false,
             },
         )
     }
-
-    #[inline]
-    async fn run(&self)-> Result<S> {
-        let call = self.call.take().ok_or_else(|| anyhow!("not call fn is none"))?;
-        call.await
-    }
 }
@@ -0,0 +1,157 @@
+use super::time::Instant;
+use super::ItemBox;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use crossbeam_queue::SegQueue;
+
+const LANES: usize = 3;
+
+/// an item in its lane, with when it got there if it is timed
+pub(crate) struct Queued<E> {
+    pub item: ItemBox<E>,
+    pub enqueued: Option<Instant>,
+}
+
+/// why a push gave its item back
+pub(crate) enum PushError<T> {
+    Full(T),
+    Closed(T),
+}
+
+/// Lane an item waits in, `High` is popped before `Normal`, `Normal` before `Low`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Default)]
+pub enum Priority {
+    High,
+    #[default]
+    Normal,
+    Low,
+}
+
+/// How the running side picks the next lane
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Scheduling {
+    /// always the highest non-empty lane, lower lanes wait as long as higher ones have work
+    #[default]
+    Strict,
+    /// like `Strict`, but a lane passed over `n` times in a row gets the next pop,
+    /// so low priority items can't starve. `n` must be positive: with 0 every lower lane
+    /// would be due all the time and the priorities would run backwards, `AQueueBuilder::scheduling` rejects it
+    Aging(u32),
+}
+
+/// Priority lanes sharing one capacity
+pub(crate) struct Lanes<E> {
+    lanes: [SegQueue<Queued<E>>; LANES],
+    // only touched by the single running side
+    skipped: [AtomicU32; LANES],
+    len: AtomicUsize,
+    cap: Option<usize>,
+    scheduling: Scheduling,
+    closed: AtomicBool,
+}
+
+impl<E> Lanes<E> {
+    pub fn new(cap: Option<usize>, scheduling: Scheduling) -> Lanes<E> {
+        Lanes {
+            lanes: [SegQueue::new(), SegQueue::new(), SegQueue::new()],
+            skipped: [AtomicU32::new(0), AtomicU32::new(0), AtomicU32::new(0)],
+            len: AtomicUsize::new(0),
+            cap,
+            scheduling,
+            closed: AtomicBool::new(false),
+        }
+    }
+
+    /// push into the lane of `priority`, give the item back if there is no room or the lanes are closed
+    #[inline]
+    pub fn push(&self, priority: Priority, item: ItemBox<E>, enqueued: Option<Instant>) -> Result<(), PushError<ItemBox<E>>> {
+        if !self.reserve() {
+            return Err(PushError::Full(item));
+        }
+        // checked after reserving, so once `close` returns `len` accounts for every item that got in
+        if self.closed.load(Ordering::SeqCst) {
+            self.len.fetch_sub(1, Ordering::SeqCst);
+            return Err(PushError::Closed(item));
+        }
+        self.lanes[priority as usize].push(Queued { item, enqueued });
+        Ok(())
+    }
+
+    #[inline]
+    fn reserve(&self) -> bool {
+        match self.cap {
+            None => {
+                self.len.fetch_add(1, Ordering::SeqCst);
+                true
+            }
+            Some(cap) => self
+                .len
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |len| if len < cap { Some(len + 1) } else { None })
+                .is_ok(),
+        }
+    }
+
+    /// pop the next item according to the scheduling,
+    /// must only be called by the running side
+    #[inline]
+    pub fn pop(&self) -> Option<Queued<E>> {
+        let lane = self.next_lane()?;
+        let item = self.lanes[lane].pop()?;
+        self.len.fetch_sub(1, Ordering::SeqCst);
+
+        if let Scheduling::Aging(_) = self.scheduling {
+            self.skipped[lane].store(0, Ordering::Relaxed);
+            for lower in lane + 1..LANES {
+                if !self.lanes[lower].is_empty() {
+                    self.skipped[lower].fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+        Some(item)
+    }
+
+    #[inline]
+    fn next_lane(&self) -> Option<usize> {
+        if let Scheduling::Aging(limit) = self.scheduling {
+            // the lowest lane that has waited long enough goes first
+            if let Some(lane) = (1..LANES)
+                .rev()
+                .find(|&lane| self.skipped[lane].load(Ordering::Relaxed) >= limit && !self.lanes[lane].is_empty())
+            {
+                return Some(lane);
+            }
+        }
+        (0..LANES).find(|&lane| !self.lanes[lane].is_empty())
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.lanes.iter().all(|lane| lane.is_empty())
+    }
+
+    #[inline]
+    pub fn is_full(&self) -> bool {
+        matches!(self.cap, Some(cap) if self.len.load(Ordering::Acquire) >= cap)
+    }
+
+    /// items queued, or about to be
+    #[inline]
+    pub fn pending(&self) -> usize {
+        self.len.load(Ordering::SeqCst)
+    }
+
+    /// refuse further pushes, true if this call closed it
+    #[inline]
+    pub fn close(&self) -> bool {
+        !self.closed.swap(true, Ordering::SeqCst)
+    }
+
+    #[inline]
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    #[inline]
+    pub fn capacity(&self) -> Option<usize> {
+        self.cap
+    }
+}
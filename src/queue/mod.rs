@@ -1,38 +1,121 @@
+#[cfg(feature = "std")]
+mod blocking;
+mod builder;
 mod item;
-use async_trait::async_trait;
+mod lanes;
+mod panic;
+mod pool;
+mod state;
+mod stats;
+mod time;
+#[cfg(feature = "std")]
+mod timeout;
+mod trace;
 use async_oneshot::Receiver;
-use concurrent_queue::ConcurrentQueue;
-pub use item::AQueueItem;
-use std::future::Future;
-use std::sync::atomic::{AtomicU8, Ordering};
-use std::hint::spin_loop;
-use anyhow::*;
+use crossbeam_queue::ArrayQueue;
+use event_listener::Event;
+pub use builder::AQueueBuilder;
+pub use item::{AQueueItem, CancelToken};
+use item::{AbortOnDrop, BatchItem, PostItem, StartGate, Turn};
+use lanes::{Lanes, PushError, Queued};
+use panic::poll_catch;
+use pool::{Pool, PoolItem};
+pub use lanes::{Priority, Scheduling};
+pub use stats::{Histogram, QueueStats};
+use state::{Hold, RunQueue, RunState};
+use stats::Stats;
+use time::Instant;
+use alloc::boxed::Box;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::future::{poll_fn, Future};
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll};
+use core::time::Duration;
+use anyhow::Result;
+use crate::{AQueueError, Spawner, Timer};
 
-#[async_trait]
-pub trait QueueItem {
-    async fn run(&self) -> Result<()>;
+/// A unit of work, polled to completion by the running side
+pub trait QueueItem<E = anyhow::Error> {
+    /// an error counts the item as failed in `AQueue::stats`, the queue goes on either way
+    fn poll_run(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), E>>;
+
+    /// `poll_run` as a future
+    #[inline]
+    fn run(mut self: Pin<&mut Self>) -> impl Future<Output = Result<(), E>>
+    where
+        Self: Sized,
+    {
+        poll_fn(move |cx| self.as_mut().poll_run(cx))
+    }
 }
 
-const IDLE: u8 = 0;
-const OPEN: u8 = 1;
+type QueueItemBox<E> = Box<dyn QueueItem<E> + Send>;
 
+/// A queued item: boxed by whoever made it, or in a block of the queue's pool
+enum ItemBox<E> {
+    Boxed(Pin<QueueItemBox<E>>),
+    Pooled(PoolItem<E>),
+}
 
-pub struct AQueue {
-    deque: ConcurrentQueue<Box<dyn QueueItem + Send + Sync>>,
-    state: AtomicU8,
-    lock:AtomicU8
+impl<E> ItemBox<E> {
+    #[inline]
+    fn poll_run(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), E>> {
+        match self {
+            ItemBox::Boxed(item) => item.as_mut().poll_run(cx),
+            ItemBox::Pooled(item) => item.poll_run(cx),
+        }
+    }
 }
 
-unsafe impl Send for AQueue {}
-unsafe impl Sync for AQueue {}
+/// item started by the running side
+struct InFlight<E> {
+    item: ItemBox<E>,
+    started: Option<Instant>,
+}
+pub(crate) type ErrorSink<E> = Arc<dyn Fn(E) + Send + Sync>;
+
+/// Runs the queued items one at a time.
+///
+/// `E` is the error type of the calls, queue failures reach them through `From<AQueueError>`.
+/// `AQueue::new()` and the other shorthands make an `anyhow` queue,
+/// a queue of another error type is made with `AQueue::<E>::default()` or `AQueueBuilder::<E>::default()`.
+pub struct AQueue<E = anyhow::Error> {
+    deque: Lanes<E>,
+    state: RunState,
+    not_full: Event,
+    // the running side left with items still queued, a waiting caller should take over
+    handoff: Event,
+    // the item that was running when the running side was dropped, resumed by whoever takes over
+    stash: ArrayQueue<InFlight<E>>,
+    spawner: Option<Arc<dyn Spawner>>,
+    // only the `std` deadlines sleep
+    #[cfg_attr(not(feature = "std"), allow(dead_code))]
+    timer: Option<Arc<dyn Timer>>,
+    error_sink: Option<ErrorSink<E>>,
+    // the running side went idle
+    drained: Event,
+    paused: AtomicBool,
+    stats: Stats,
+    // blocks for the items made by `run` and friends, see `AQueueBuilder::pool`
+    pool: Arc<Pool>,
+    // the `Arc` holding a queue with a spawner, so it can start a task running itself,
+    // see `AQueue::into_shared`
+    this: Option<Weak<AQueue<E>>>,
+    // run by a driver task, see `AQueueBuilder::build_driven`
+    driven: bool,
+    // items / time one turn of `run_ing` may take before letting someone else run
+    drain_budget: Option<usize>,
+    drain_time: Option<Duration>
+}
 
-impl Default for AQueue{
+impl<E> Default for AQueue<E>
+where
+    E: From<AQueueError> + Send + Sync + 'static,
+{
     fn default() -> Self {
-        AQueue {
-            deque: ConcurrentQueue::unbounded(),
-            state: AtomicU8::new(IDLE),
-            lock:AtomicU8::new(IDLE)
-        }
+        AQueueBuilder::default().build()
     }
 }
 
@@ -41,69 +124,712 @@ impl AQueue {
         AQueue::default()
     }
 
+    /// Create a bounded queue holding at most `cap` pending items.
+    /// When the queue is full, `run`/`push` wait for a free slot instead of growing.
+    ///
+    /// # Panics
+    ///
+    /// If `cap` is zero.
+    pub fn with_capacity(cap: usize) -> AQueue {
+        AQueue::builder().capacity(cap).build()
+    }
+
+    #[inline]
+    pub fn builder() -> AQueueBuilder {
+        AQueueBuilder::default()
+    }
+}
+
+/// A queue to share, see `Actor::with_queue`: an `AQueue`, put behind an `Arc` by `AQueue::into_shared`,
+/// or one already shared
+pub trait IntoSharedQueue<E> {
+    fn into_shared_queue(self) -> Arc<AQueue<E>>;
+}
+
+impl<E> IntoSharedQueue<E> for AQueue<E>
+where
+    E: From<AQueueError> + Send + Sync + 'static,
+{
+    #[inline]
+    fn into_shared_queue(self) -> Arc<AQueue<E>> {
+        self.into_shared()
+    }
+}
+
+impl<E> IntoSharedQueue<E> for Arc<AQueue<E>> {
+    #[inline]
+    fn into_shared_queue(self) -> Arc<AQueue<E>> {
+        self
+    }
+}
+
+impl<E> AQueue<E>
+where
+    E: From<AQueueError> + Send + Sync + 'static,
+{
+    #[inline]
+    fn from_builder(builder: AQueueBuilder<E>) -> AQueue<E> {
+        AQueue {
+            deque: Lanes::new(builder.capacity, builder.scheduling),
+            state: RunState::new(),
+            not_full: Event::new(),
+            handoff: Event::new(),
+            stash: ArrayQueue::new(1),
+            spawner: builder.spawner,
+            timer: builder.timer,
+            error_sink: builder.error_sink,
+            drained: Event::new(),
+            paused: AtomicBool::new(false),
+            stats: Stats::new(builder.stats_sample),
+            pool: Arc::new(Pool::new(builder.pool)),
+            this: None,
+            driven: false,
+            drain_budget: builder.drain_budget,
+            drain_time: builder.drain_time
+        }
+    }
+
+    /// Put the queue behind an `Arc`, the way `AQueueBuilder::build_shared` does.
+    /// A queue with a spawner built this way starts a task on it whenever it is left with items
+    /// and nobody to run them, e.g. when a caller running it is dropped with posted items queued;
+    /// a plain `Arc::new` can't give it a handle on itself.
+    #[inline]
+    pub fn into_shared(self) -> Arc<AQueue<E>> {
+        Arc::new_cyclic(|this| AQueue {
+            this: self.spawner.as_ref().map(|_| this.clone()),
+            ..self
+        })
+    }
+
+    /// Max number of pending items, `None` if unbounded
+    #[inline]
+    pub fn capacity(&self) -> Option<usize> {
+        self.deque.capacity()
+    }
+
+    /// Stop taking new items: `run`, `push`, `post`... fail with `AQueueError::Closed` from now on,
+    /// callers waiting for a free slot included. Items already queued still run, see `drain`.
+    #[inline]
+    pub fn close(&self) {
+        if self.deque.close() {
+            self.not_full.notify(usize::MAX);
+        }
+    }
+
+    #[inline]
+    pub fn is_closed(&self) -> bool {
+        self.deque.is_closed()
+    }
+
+    /// Wait until every queued item has run and nothing is running.
+    /// If nobody is running the queue this runs it, so posted items are not left behind.
+    /// Usually called after `close`, otherwise new items may keep it waiting.
+    pub async fn drain(&self) {
+        loop {
+            self.drive().await;
+            // listen after our own run, its release would wake us right away
+            let listener = self.drained.listen();
+            if self.is_drained() {
+                return;
+            }
+            listener.await;
+        }
+    }
+
+    /// Hold the queue: items are still accepted (up to the capacity) but none is started
+    /// until `resume`. The item running when this is called finishes first.
+    #[inline]
+    pub fn pause(&self) {
+        self.paused.store(true, Ordering::SeqCst);
+    }
+
+    /// Start running the held items again, in the order they would have run.
+    /// Waiting callers pick the queue up again, and a queue with a spawner shared by `AQueue::into_shared`
+    /// (or `build_shared`, `build_driven`, `Actor::with_queue`) starts a task on it for the posted items;
+    /// otherwise those wait for the next caller or `drain`.
+    #[inline]
+    pub fn resume(&self) {
+        if !self.paused.swap(false, Ordering::SeqCst) {
+            return;
+        }
+        // a running side on its way out looks again
+        self.notify();
+        // whoever waits on the queue takes it up again
+        self.handoff.notify(1);
+        self.not_full.notify(1);
+        self.drained.notify(usize::MAX);
+        if !self.deque.is_empty() {
+            self.restart();
+        }
+    }
+
+    #[inline]
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    /// Items accepted but not started yet, those held while paused included
+    #[inline]
+    pub fn pending(&self) -> usize {
+        self.deque.pending()
+    }
+
+    /// Snapshot of the queue's counters: depth, throughput, wait and run times
+    #[inline]
+    pub fn stats(&self) -> QueueStats {
+        self.stats.snapshot(self.deque.pending(), self.pool.counts())
+    }
+
+    #[inline]
+    fn is_drained(&self) -> bool {
+        self.deque.pending() == 0 && !self.is_running() && self.stash.is_empty()
+    }
+
     #[inline]
-    pub async fn run<A, T, S>(&self, call: impl FnOnce(A) -> T , arg: A) -> Result<S>
+    pub async fn run<A, T, S>(&self, call: impl FnOnce(A) -> T , arg: A) -> Result<S, E>
     where
-        T: Future<Output = Result<S>> + Send  + 'static,
+        T: Future<Output = Result<S, E>> + Send  + 'static,
         S: 'static+Sync+Send,
         A: Send + Sync + 'static, {
 
         //
-        let (rx,item)=AQueueItem::new(Box::pin(call(arg)));
+        let (rx,item)=PoolItem::new(&self.pool, call(arg), None);
+        self.push_item(Priority::Normal, rx, ItemBox::Pooled(item)).await
+    }
+
+    /// Like `run`, for synchronous code: parks the thread until the result is ready, no runtime needed.
+    ///
+    /// If nobody else is running the queue this thread runs it, and with it the items other callers queued
+    /// before ours, outside of any runtime: an item using the runtime's timers or IO then panics.
+    /// When async code shares the queue, make it a driver queue (`AQueueBuilder::build_driven`):
+    /// the thread then only starts the driver task and every item runs on the runtime.
+    /// The spawner is called from this thread, so it must work off the runtime,
+    /// e.g. `runtime::Tokio::current()` or a closure holding a `tokio::runtime::Handle`.
+    ///
+    /// Fails with `AQueueError::BlockingInAsync` when called from a queue item or another blocking call,
+    /// and, with the `tokio` feature, from inside a tokio runtime.
+    #[cfg(feature = "std")]
+    #[inline]
+    pub fn run_blocking<A, T, S>(&self, call: impl FnOnce(A) -> T , arg: A) -> Result<S, E>
+    where
+        T: Future<Output = Result<S, E>> + Send  + 'static,
+        S: 'static+Sync+Send,
+        A: Send + Sync + 'static, {
+        if blocking::in_async() {
+            return Err(AQueueError::BlockingInAsync.into());
+        }
+        blocking::block_on(self.run(call, arg))
+    }
+
+    /// Like `run`, but the item waits in the lane of `priority`,
+    /// so it goes ahead of lower priority items already queued.
+    #[inline]
+    pub async fn run_with_priority<A, T, S>(&self, priority: Priority, call: impl FnOnce(A) -> T , arg: A) -> Result<S, E>
+    where
+        T: Future<Output = Result<S, E>> + Send  + 'static,
+        S: 'static+Sync+Send,
+        A: Send + Sync + 'static, {
+        let (rx,item)=PoolItem::new(&self.pool, call(arg), None);
+        self.push_item(priority, rx, ItemBox::Pooled(item)).await
+    }
+
+    /// Like `run`, with deadlines:
+    /// - `wait`: the item must start within it, waiting for a free slot included,
+    ///   otherwise it is dropped without running and the call fails with `AQueueError::WaitTimeout`
+    /// - `exec`: once started the item must finish within it,
+    ///   otherwise it is dropped mid-way and the call fails with `AQueueError::ExecTimeout`
+    #[cfg(feature = "std")]
+    #[inline]
+    pub async fn run_timeout<A, T, S>(&self, wait: Option<Duration>, exec: Option<Duration>, call: impl FnOnce(A) -> T , arg: A) -> Result<S, E>
+    where
+        T: Future<Output = Result<S, E>> + Send  + 'static,
+        S: 'static+Sync+Send,
+        A: Send + Sync + 'static, {
+        let call = timeout::ExecTimeout::new(call(arg), exec, self.timer.clone());
+        let gate = StartGate::new(wait.map(|wait| Instant::now() + wait));
+        let abort = AbortOnDrop::new(gate.clone());
+        let (rx, item) = PoolItem::new(&self.pool, call, Some(gate.clone()));
+        let mut wait = wait.map(|wait| timeout::sleep(self.timer.as_deref(), wait));
+
+        let mut push = core::pin::pin!(self.push_wait(Priority::Normal, ItemBox::Pooled(item)));
+        poll_fn(|cx| {
+            if let Poll::Ready(r) = push.as_mut().poll(cx) {
+                return Poll::Ready(r);
+            }
+            match wait.as_mut().map(|wait| wait.as_mut().poll(cx)) {
+                Some(Poll::Ready(())) => Poll::Ready(Err(AQueueError::WaitTimeout)),
+                _ => Poll::Pending,
+            }
+        })
+        .await
+        .map_err(E::from)?;
+
+        let r = self
+            .wait_result_or(rx, |cx| {
+                if let Some(Poll::Ready(())) = wait.as_mut().map(|wait| wait.as_mut().poll(cx)) {
+                    wait = None;
+                    if gate.cancel() {
+                        return Poll::Ready(AQueueError::WaitTimeout.into());
+                    }
+                    // already running, the exec deadline takes over
+                }
+                Poll::Pending
+            })
+            .await;
+        abort.disarm();
+        r
+    }
+
+    /// Like `run`, but `call` also gets a `CancelToken`.
+    /// If this call is dropped before the item starts, the item is dropped without running,
+    /// if it is dropped while the item runs, the token reports it so the item can stop early.
+    #[inline]
+    pub async fn run_cancellable<A, T, S>(&self, call: impl FnOnce(A, CancelToken) -> T , arg: A) -> Result<S, E>
+    where
+        T: Future<Output = Result<S, E>> + Send  + 'static,
+        S: 'static+Sync+Send,
+        A: Send + Sync + 'static, {
+        let gate = StartGate::new(None);
+        let abort = AbortOnDrop::new(gate.clone());
+        let (rx, item) = PoolItem::new(&self.pool, call(arg, CancelToken::new(gate.clone())), Some(gate));
+        let r = self.push_item(Priority::Normal, rx, ItemBox::Pooled(item)).await;
+        abort.disarm();
+        r
+    }
+
+    /// Run every call of `calls` back to back as one block: no other item runs in between,
+    /// and it only takes one slot of a bounded queue.
+    /// Each call gets its own clone of `arg`, the results come back in the same order.
+    #[inline]
+    pub async fn run_batch<A, F, T, S>(&self, calls: impl IntoIterator<Item = F>, arg: A) -> Result<Vec<Result<S, E>>, E>
+    where
+        F: FnOnce(A) -> T,
+        T: Future<Output = Result<S, E>> + Send  + 'static,
+        S: 'static+Sync+Send,
+        A: Clone + Send + Sync + 'static, {
+        let calls = calls.into_iter().map(|call| call(arg.clone())).collect();
+        let (rx,item)=BatchItem::new(calls);
         self.push(rx,Box::new(item)).await
     }
 
-    /// # Safety
+    /// Queue `call` and return at once without waiting for it to run.
+    /// Its error, if any, goes to the error sink set with `AQueueBuilder::error_sink`.
+    /// Fails with `AQueueError::Full` if the bounded queue has no free slot.
+    ///
+    /// If nobody is running the queue, a task running it is started on the spawner set with `AQueueBuilder::spawner`,
+    /// and again if a caller running it is dropped before getting to the item (see `AQueue::into_shared`);
+    /// without a spawner the item waits for the next `run` call or a `run_ing` task.
+    #[inline]
+    pub fn post<A, T, S>(self: &Arc<Self>, call: impl FnOnce(A) -> T , arg: A) -> Result<(), E>
+    where
+        T: Future<Output = Result<S, E>> + Send  + 'static,
+        S: 'static+Send,
+        A: Send + Sync + 'static, {
+        let item = PostItem::new(call(arg), self.error_sink.clone());
+        self.try_push_item(Priority::Normal, ItemBox::Boxed(Box::pin(item))).map_err(|err| E::from(push_error(err)))?;
+        self.spawn_run_ing(self);
+        Ok(())
+    }
+
+    /// Like `run`, but never waits for a free slot:
+    /// if the bounded queue is full it fails at once with `AQueueError::Full`.
+    #[inline]
+    pub async fn try_run<A, T, S>(&self, call: impl FnOnce(A) -> T , arg: A) -> Result<S, E>
+    where
+        T: Future<Output = Result<S, E>> + Send  + 'static,
+        S: 'static+Sync+Send,
+        A: Send + Sync + 'static, {
+        if self.deque.is_closed() {
+            return Err(AQueueError::Closed.into());
+        }
+        if self.deque.is_full() {
+            return Err(AQueueError::Full.into());
+        }
+        let (rx,item)=PoolItem::new(&self.pool, call(arg), None);
+        self.try_push_item(Priority::Normal, ItemBox::Pooled(item)).map_err(|err| E::from(push_error(err)))?;
+        self.wait_result(rx).await
+    }
+
+    /// Like `run`, but the call may borrow from the caller.
+    ///
+    /// The call itself is not queued: an item holding its place is, and once that item comes up
+    /// it holds the queue while the call runs right here, in this future, so the call still runs
+    /// after the items queued before it and alongside none of them.
     ///
-    /// 捕获闭包的借用参数，可能会导致问题，请勿乱用
+    /// Dropping this future, before or during the call, lets the queue go on.
+    /// Leaking it mid-call (e.g. with `mem::forget`) leaves the queue stuck, like a leaked lock guard.
     #[inline]
-    pub async unsafe fn ref_run<'a,A, T, S>(&'a self, call: impl FnOnce(A) -> T , arg: A) -> Result<S>
+    pub async fn ref_run<'a,A, T, S>(&'a self, call: impl FnOnce(A) -> T , arg: A) -> Result<S, E>
         where
-            T: Future<Output = Result<S>> + Send  + 'a,
+            T: Future<Output = Result<S, E>> + Send  + 'a,
             S: 'static+Sync+Send,
             A: Send + Sync + 'static, {
+        let (turn, item) = Turn::new();
+        self.push_wait(Priority::Normal, ItemBox::Boxed(Box::pin(item))).await.map_err(E::from)?;
+        self.wait_turn(&turn).await;
+        // a panic in the call comes back as `Panicked`, like from any other item
+        let mut call = core::pin::pin!(call(arg));
+        let r = poll_fn(|cx| poll_catch(call.as_mut(), cx)).await;
+        drop(turn);
+        // the queue was held for us, carry on with what queued up behind
+        self.drive().await;
+        r
+    }
+
+    /// wait for the turn to be granted, running the queue meanwhile whenever nobody else does
+    #[inline]
+    async fn wait_turn(&self, turn: &Turn) {
+        // our own `run_ing` stops at the turn's item until the turn is over,
+        // so it is polled alongside the wait rather than awaited, and dropped once granted
+        let mut drive = None;
+        loop {
+            if turn.is_granted() {
+                return;
+            }
+            let mut granted = turn.listen();
+            let mut handoff = self.handoff.listen();
+            if drive.is_none() {
+                drive = Some(Box::pin(self.drive()));
+            }
+            poll_fn(|cx| {
+                if let Some(fut) = drive.as_mut() {
+                    if fut.as_mut().poll(cx).is_ready() {
+                        drive = None;
+                    }
+                }
+                if turn.is_granted() || Pin::new(&mut granted).poll(cx).is_ready() {
+                    return Poll::Ready(());
+                }
+                // someone else runs the queue, take over if they leave before our turn
+                if drive.is_none() {
+                    return Pin::new(&mut handoff).poll(cx);
+                }
+                Poll::Pending
+            })
+            .await;
+        }
+    }
+
+    #[inline]
+    pub async fn push<T>(&self, rx:Receiver<Result<T, E>>, item: Box<dyn QueueItem<E> + Send>) -> Result<T, E> {
+        self.push_with_priority(Priority::Normal, rx, item).await
+    }
+
+    #[inline]
+    pub async fn push_with_priority<T>(&self, priority: Priority, rx:Receiver<Result<T, E>>, item: Box<dyn QueueItem<E> + Send>) -> Result<T, E> {
+        self.push_item(priority, rx, ItemBox::Boxed(Box::into_pin(item))).await
+    }
+
+    /// push item without waiting, fails with `AQueueError::Full` if the queue is full
+    #[inline]
+    pub async fn try_push<T>(&self, rx:Receiver<Result<T, E>>, item: Box<dyn QueueItem<E> + Send>) -> Result<T, E> {
+        self.try_push_item(Priority::Normal, ItemBox::Boxed(Box::into_pin(item))).map_err(|err| E::from(push_error(err)))?;
+        self.wait_result(rx).await
+    }
+
+    #[inline]
+    async fn push_item<T, X>(&self, priority: Priority, rx: impl Future<Output = Result<Result<T, E>, X>> + Unpin, item: ItemBox<E>) -> Result<T, E> {
+        self.push_wait(priority, item).await.map_err(E::from)?;
+        self.wait_result(rx).await
+    }
+
+    #[inline]
+    async fn wait_result<T, X>(&self, rx: impl Future<Output = Result<Result<T, E>, X>> + Unpin) -> Result<T, E> {
+        self.wait_result_or(rx, |_| Poll::Pending).await
+    }
+
+    /// wait for the result, taking over the running side whenever it is left with items queued;
+    /// `give_up` may end the wait early with an error
+    #[inline]
+    async fn wait_result_or<T, X>(&self, mut rx: impl Future<Output = Result<Result<T, E>, X>> + Unpin, mut give_up: impl FnMut(&mut Context<'_>) -> Poll<E>) -> Result<T, E> {
+        self.drive_or(&mut give_up).await?;
+        loop {
+            // most of the time it is done by now, don't bother listening
+            if let Some(r) = poll_fn(|cx| Poll::Ready(poll_result(&mut rx, cx))).await {
+                return r;
+            }
+
+            let mut handoff = self.handoff.listen();
+            // the running side may have left before we started listening
+            self.drive_or(&mut give_up).await?;
+            let r = poll_fn(|cx| {
+                if let Some(r) = poll_result(&mut rx, cx) {
+                    return Poll::Ready(Some(r));
+                }
+                if let Poll::Ready(err) = give_up(cx) {
+                    return Poll::Ready(Some(Err(err)));
+                }
+                Pin::new(&mut handoff).poll(cx).map(|()| None)
+            })
+            .await;
+            if let Some(r) = r {
+                return r;
+            }
+        }
+    }
+
+    /// `drive`, unless `give_up` ends the wait first: our `run_ing` is then dropped,
+    /// handing the queue and the item in flight over to whoever takes over
+    #[inline]
+    async fn drive_or(&self, give_up: &mut impl FnMut(&mut Context<'_>) -> Poll<E>) -> Result<(), E> {
+        let mut drive = core::pin::pin!(self.drive());
+        poll_fn(|cx| {
+            if drive.as_mut().poll(cx).is_ready() {
+                return Poll::Ready(Ok(()));
+            }
+            give_up(cx).map(Err)
+        })
+        .await
+    }
+
+    /// push item, if the queue is full wait until the running side pops one
+    #[inline]
+    async fn push_wait(&self, priority: Priority, mut item: ItemBox<E>) -> Result<(), AQueueError> {
+        loop {
+            item = match self.try_push_item(priority, item) {
+                Ok(()) => return Ok(()),
+                Err(PushError::Full(item)) => item,
+                Err(err) => return Err(push_error(err)),
+            };
+
+            // register first, then retry, so a pop between the two can't be missed
+            let listener = self.not_full.listen();
+            item = match self.try_push_item(priority, item) {
+                Ok(()) => return Ok(()),
+                Err(PushError::Full(item)) => item,
+                Err(err) => return Err(push_error(err)),
+            };
+            if !self.driven && !self.is_running() && !self.is_paused() {
+                // full but nobody is running it, do it ourselves
+                let _ = self.run_ing().await;
+            } else {
+                self.drive().await;
+                listener.await;
+            }
+        }
+    }
+
+    #[inline]
+    fn try_push_item(&self, priority: Priority, item: ItemBox<E>) -> Result<(), PushError<ItemBox<E>>> {
+        let r = self.deque.push(priority, item, self.stats.stamp());
+        match r {
+            Ok(()) => {
+                self.notify();
+                trace::enqueued(priority, self.deque.pending())
+            }
+            // it was counted for a moment, `drain` may have seen it
+            Err(PushError::Closed(_)) => {
+                self.drained.notify(usize::MAX);
+            }
+            Err(PushError::Full(_)) => {}
+        }
+        r
+    }
+
+    #[inline]
+    fn over_budget(&self, used: usize, turn: Option<Instant>) -> bool {
+        if matches!(self.drain_budget, Some(budget) if used >= budget) {
+            return true;
+        }
+        matches!((self.drain_time, turn), (Some(limit), Some(turn)) if turn.elapsed() >= limit)
+    }
 
-        let (rx,item):(Receiver<Result<S>>,Box<dyn QueueItem + Send + Sync+'a>)={
-            let (rx,item)=AQueueItem::new(Box::pin(call(arg)));
-            (rx,Box::new(item))
-        };
+    /// Get the queue running if nobody is: start the driver task on queues that have one,
+    /// otherwise run it right here
+    #[inline]
+    async fn drive(&self) {
+        if self.driven {
+            self.restart();
+        } else {
+            let _ = self.run_ing().await;
+        }
+    }
 
-        let item=Box::from_raw(std::mem::transmute(Box::into_raw(item)));
-        self.push(rx,item).await
+    /// someone holds the running side
+    #[inline]
+    fn is_running(&self) -> bool {
+        self.state.is_running()
     }
 
+    /// tell the running side there is something new, called once the item is in the lanes
     #[inline]
-    pub async fn push<T>(&self, rx:Receiver<Result<T>>, item: Box<dyn QueueItem + Send + Sync>) -> Result<T> {
-        self.deque.push(item)
-            .map_err(|err| anyhow!(err.to_string()))?;
+    fn notify(&self) {
+        self.state.notify();
+    }
 
-        while self.lock.load(Ordering::Relaxed) == OPEN {
-            spin_loop();
+    /// start a task running the queue on the spawner, if it has one and sits in an `Arc` made by us
+    #[inline]
+    fn restart(&self) {
+        if let Some(this) = self.this.as_ref().and_then(Weak::upgrade) {
+            self.spawn_run_ing(&this);
         }
+    }
 
-        self.run_ing().await?;
-        rx.await.map_err(|_| anyhow!("tx is close"))?
+    /// start a task running the queue on the spawner, if there is one and the queue is idle
+    #[inline]
+    fn spawn_run_ing(&self, this: &Arc<Self>) {
+        if let Some(ref spawner) = self.spawner {
+            if !self.is_running() && !self.is_paused() {
+                let queue = this.clone();
+                spawner.spawn(Box::pin(async move {
+                    let _ = queue.run_ing().await;
+                }));
+            }
+        }
     }
 
+    /// Drain the queue if nobody else is doing it.
+    /// Only one task at a time runs items, the others just wait for their result.
+    ///
+    /// An item failing, e.g. because its caller is gone, only concerns that item:
+    /// the loop goes on with the next one, so this always returns `Ok`.
+    ///
+    /// With a drain budget (`AQueueBuilder::drain_budget`, `AQueueBuilder::drain_time`) it lets go of the queue
+    /// once the budget is spent, so a waiting caller can take over, and yields to the runtime
+    /// before trying to carry on.
     #[inline]
-    pub async fn run_ing(&self) -> Result<()> {
-        if self.state.compare_exchange(IDLE, OPEN, Ordering::Acquire, Ordering::Acquire) == Ok(IDLE) {
-            'recv:
+    pub async fn run_ing(&self) -> Result<(), E> {
+        while let Some(hold) = Hold::take(self) {
+            // also releases the running side if this future is dropped mid-way,
+            // handing the item in flight over to whoever takes over
+            let mut running = Running { current: self.stash.pop(), hold };
+            let turn = self.drain_time.and_then(|_| time::now());
             loop {
-                let item = {
-                    self.lock.store(OPEN, Ordering::Release);
-                    match self.deque.pop() {
-                        Ok(p) => p,
-                        _ => break 'recv
+                if running.current.is_none() {
+                    let Some(queued) = running.hold.next(|used| self.over_budget(used, turn)) else {
+                        break;
+                    };
+                    if self.deque.capacity().is_some() {
+                        self.not_full.notify(1);
                     }
-                };
-                self.lock.store(IDLE, Ordering::Release);
-                item.run().await?;
+                    self.stats.popped(self.deque.pending() + 1);
+                    // only the sampled items read the clock
+                    let started = queued.enqueued.and_then(|enqueued| {
+                        let now = time::now()?;
+                        self.stats.wait(now.saturating_duration_since(enqueued));
+                        Some(now)
+                    });
+                    running.current = Some(InFlight {
+                        item: queued.item,
+                        started,
+                    });
+                }
+                if let Some(current) = running.current.as_mut() {
+                    let ok = poll_fn(|cx| {
+                        #[cfg(feature = "std")]
+                        let _enter = blocking::enter();
+                        current.item.poll_run(cx).map(|r| r.is_ok())
+                    })
+                    .await;
+                    self.stats.ran(current.started.map(|started| started.elapsed()), ok);
+                }
+                running.current = None;
             }
+            let spent = running.hold.spent();
+            drop(running);
 
-            self.state.store(IDLE, Ordering::Release);
-            self.lock.store(IDLE,Ordering::Release);
+            if !spent {
+                break;
+            }
+            // the release woke a waiting caller, give it and the other tasks a chance to run
+            YieldNow(false).await;
         }
         Ok(())
     }
 }
+
+impl<E> RunQueue for AQueue<E>
+where
+    E: From<AQueueError> + Send + Sync + 'static,
+{
+    type Item = Queued<E>;
+
+    #[inline]
+    fn run_state(&self) -> &RunState {
+        &self.state
+    }
+
+    #[inline]
+    fn is_paused(&self) -> bool {
+        AQueue::is_paused(self)
+    }
+
+    #[inline]
+    fn is_empty(&self) -> bool {
+        self.deque.is_empty()
+    }
+
+    #[inline]
+    fn pop(&self) -> Option<Queued<E>> {
+        self.deque.pop()
+    }
+
+    #[inline]
+    fn has_work(&self) -> bool {
+        !self.stash.is_empty() || (!self.deque.is_empty() && !AQueue::is_paused(self))
+    }
+
+    #[inline]
+    fn hand_over(&self) {
+        // wake someone to carry on, and start a task in case nobody waits, as with posted items
+        self.handoff.notify(1);
+        self.not_full.notify(1);
+        self.restart();
+    }
+
+    #[inline]
+    fn left(&self) {
+        self.drained.notify(usize::MAX);
+    }
+}
+
+/// Pending once, so the runtime gets to run the other ready tasks before us
+struct YieldNow(bool);
+
+impl Future for YieldNow {
+    type Output = ();
+
+    #[inline]
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.0 {
+            return Poll::Ready(());
+        }
+        self.0 = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+#[inline]
+fn push_error<T>(err: PushError<T>) -> AQueueError {
+    match err {
+        PushError::Full(_) => AQueueError::Full,
+        PushError::Closed(_) => AQueueError::Closed,
+    }
+}
+
+#[inline]
+fn poll_result<T, E: From<AQueueError>, X>(rx: &mut (impl Future<Output = Result<Result<T, E>, X>> + Unpin), cx: &mut Context<'_>) -> Option<Result<T, E>> {
+    match Pin::new(rx).poll(cx) {
+        Poll::Ready(r) => Some(r.map_err(|_| E::from(AQueueError::SenderDropped)).and_then(|r| r)),
+        Poll::Pending => None,
+    }
+}
+
+/// Holds the running side of an `AQueue` with the item in flight,
+/// stashed for whoever takes over if this is dropped before it finishes
+struct Running<'a, E: From<AQueueError> + Send + Sync + 'static> {
+    current: Option<InFlight<E>>,
+    // lets go of the running side once `drop` below stashed the item
+    hold: Hold<'a, AQueue<E>>,
+}
+
+impl<E: From<AQueueError> + Send + Sync + 'static> Drop for Running<'_, E> {
+    #[inline]
+    fn drop(&mut self) {
+        if let Some(current) = self.current.take() {
+            // only the running side touches the stash, so there is room
+            let _ = self.hold.queue().stash.push(current);
+        }
+    }
+}
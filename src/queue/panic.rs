@@ -0,0 +1,43 @@
+use crate::AQueueError;
+use anyhow::Result;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+/// Poll the call, turning a panic into `AQueueError::Panicked`
+/// so it reaches the caller instead of unwinding through the running side.
+/// Without `std` panics can't be caught, it only polls.
+#[cfg(feature = "std")]
+#[inline]
+pub(crate) fn poll_catch<F, S, E>(fut: Pin<&mut F>, cx: &mut Context<'_>) -> Poll<Result<S, E>>
+where
+    F: Future<Output = Result<S, E>>,
+    E: From<AQueueError>,
+{
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+    match catch_unwind(AssertUnwindSafe(|| fut.poll(cx))) {
+        Ok(poll) => poll,
+        Err(payload) => Poll::Ready(Err(AQueueError::Panicked(panic_message(payload)).into())),
+    }
+}
+
+#[cfg(not(feature = "std"))]
+#[inline]
+pub(crate) fn poll_catch<F, S, E>(fut: Pin<&mut F>, cx: &mut Context<'_>) -> Poll<Result<S, E>>
+where
+    F: Future<Output = Result<S, E>>,
+    E: From<AQueueError>,
+{
+    fut.poll(cx)
+}
+
+#[cfg(feature = "std")]
+fn panic_message(payload: Box<dyn std::any::Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(msg) => *msg,
+        Err(payload) => match payload.downcast::<&'static str>() {
+            Ok(msg) => msg.to_string(),
+            Err(_) => "unknown panic".to_string(),
+        },
+    }
+}
@@ -0,0 +1,313 @@
+use super::item::{Call, ResultTx, StartGate};
+use super::stats::{read, Counter};
+use super::QueueItem;
+use crate::AQueueError;
+use alloc::alloc::{alloc, dealloc, handle_alloc_error, Layout};
+use alloc::sync::Arc;
+use core::cell::UnsafeCell;
+use core::future::Future;
+use core::mem::ManuallyDrop;
+use core::pin::Pin;
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{AtomicU8, Ordering};
+use core::task::{Context, Poll, Waker};
+use crossbeam_queue::ArrayQueue;
+
+/// Blocks kept by the pool, items that don't fit one are allocated as usual
+const BLOCK: Layout = match Layout::from_size_align(256, 16) {
+    Ok(layout) => layout,
+    Err(_) => panic!("bad block layout"),
+};
+
+struct Block(NonNull<u8>);
+
+unsafe impl Send for Block {}
+
+/// Free list of the blocks holding an item and its caller's result slot,
+/// so a hot queue reuses them instead of going to the allocator for every call
+pub(crate) struct Pool {
+    // `None` when pooling is off, every block then comes from the allocator at its own size
+    free: Option<ArrayQueue<Block>>,
+    hits: Counter,
+    misses: Counter,
+}
+
+impl Pool {
+    #[inline]
+    pub fn new(size: usize) -> Pool {
+        Pool {
+            free: if size > 0 { Some(ArrayQueue::new(size)) } else { None },
+            hits: Counter::new(0),
+            misses: Counter::new(0),
+        }
+    }
+
+    #[inline]
+    fn pooled(&self, layout: Layout) -> bool {
+        self.free.is_some() && layout.size() <= BLOCK.size() && layout.align() <= BLOCK.align()
+    }
+
+    #[inline]
+    fn alloc(&self, layout: Layout) -> NonNull<u8> {
+        let layout = if self.pooled(layout) {
+            if let Some(block) = self.free.as_ref().and_then(|free| free.pop()) {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                return block.0;
+            }
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            BLOCK
+        } else {
+            layout
+        };
+        // Safety: the layouts are never zero sized, they all hold a `Header`
+        match NonNull::new(unsafe { alloc(layout) }) {
+            Some(ptr) => ptr,
+            None => handle_alloc_error(layout),
+        }
+    }
+
+    /// # Safety
+    ///
+    /// `ptr` must come from `alloc` with the same `layout`
+    #[inline]
+    unsafe fn free(&self, ptr: NonNull<u8>, layout: Layout) {
+        if !self.pooled(layout) {
+            dealloc(ptr.as_ptr(), layout);
+        } else if let Some(Err(block)) = self.free.as_ref().map(|free| free.push(Block(ptr))) {
+            dealloc(block.0.as_ptr(), BLOCK);
+        }
+    }
+
+    /// blocks reused / blocks that had to be allocated, items too big for a block aside
+    #[inline]
+    pub fn counts(&self) -> (u64, u64) {
+        (read(&self.hits), read(&self.misses))
+    }
+}
+
+impl Drop for Pool {
+    fn drop(&mut self) {
+        if let Some(ref free) = self.free {
+            while let Some(block) = free.pop() {
+                unsafe { dealloc(block.0.as_ptr(), BLOCK) }
+            }
+        }
+    }
+}
+
+// the sender is done: the value is in, or it was dropped without one
+const READY: u8 = 1;
+// the receiver is storing its waker
+const REGISTERING: u8 = 2;
+// the receiver is gone
+const CLOSED: u8 = 4;
+
+/// Start of every block: the result slot, and what the last side out needs to free the block
+#[repr(C)]
+struct Header<T> {
+    // the item and the receiver, the last one to go frees the block
+    refs: AtomicU8,
+    state: AtomicU8,
+    layout: Layout,
+    pool: ManuallyDrop<Arc<Pool>>,
+    waker: UnsafeCell<Option<Waker>>,
+    value: UnsafeCell<Option<T>>,
+}
+
+/// drop one side's hold on the block, freeing it if it was the last
+///
+/// # Safety
+///
+/// Each side calls it once, and doesn't touch the block after
+unsafe fn release<T>(header: NonNull<Header<T>>) {
+    let h = header.as_ptr();
+    if (*h).refs.fetch_sub(1, Ordering::AcqRel) != 1 {
+        return;
+    }
+    let pool = ManuallyDrop::take(&mut (*h).pool);
+    let layout = (*h).layout;
+    ptr::drop_in_place((*h).waker.get());
+    ptr::drop_in_place((*h).value.get());
+    pool.free(header.cast(), layout);
+}
+
+/// The item's end of the result slot
+pub(crate) struct SlotTx<T> {
+    header: NonNull<Header<T>>,
+    done: bool,
+}
+
+// the value goes to the receiver's thread
+unsafe impl<T: Send> Send for SlotTx<T> {}
+
+impl<T> SlotTx<T> {
+    #[inline]
+    fn complete(&mut self) -> u8 {
+        self.done = true;
+        let header = unsafe { self.header.as_ref() };
+        let prev = header.state.fetch_or(READY, Ordering::AcqRel);
+        // while the receiver registers the waker is its own, it sees READY once it is done
+        if prev & REGISTERING == 0 {
+            if let Some(waker) = unsafe { (*header.waker.get()).take() } {
+                waker.wake();
+            }
+        }
+        prev
+    }
+}
+
+impl<T> ResultTx<T> for SlotTx<T> {
+    #[inline]
+    fn is_closed(&self) -> bool {
+        unsafe { self.header.as_ref() }.state.load(Ordering::Acquire) & CLOSED != 0
+    }
+
+    #[inline]
+    fn send(&mut self, value: T) -> bool {
+        // the receiver only reads it once READY is set
+        unsafe { *self.header.as_ref().value.get() = Some(value) };
+        self.complete() & CLOSED == 0
+    }
+}
+
+impl<T> Drop for SlotTx<T> {
+    #[inline]
+    fn drop(&mut self) {
+        if !self.done {
+            // dropped without a value, the receiver gets `Closed`
+            self.complete();
+        }
+    }
+}
+
+/// The receiver is gone, or the item was dropped without a result
+#[derive(Debug)]
+pub(crate) struct Closed;
+
+/// The caller's end of the result slot
+pub(crate) struct SlotRx<T> {
+    header: NonNull<Header<T>>,
+}
+
+unsafe impl<T: Send> Send for SlotRx<T> {}
+
+impl<T> SlotRx<T> {
+    #[inline]
+    fn take(&mut self) -> Result<T, Closed> {
+        unsafe { (*self.header.as_ref().value.get()).take() }.ok_or(Closed)
+    }
+}
+
+impl<T> Future for SlotRx<T> {
+    type Output = Result<T, Closed>;
+
+    #[inline]
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let header = unsafe { self.header.as_ref() };
+        if header.state.load(Ordering::Acquire) & READY != 0 {
+            return Poll::Ready(self.take());
+        }
+        if header.state.fetch_or(REGISTERING, Ordering::Acquire) & READY != 0 {
+            header.state.fetch_and(!REGISTERING, Ordering::Release);
+            return Poll::Ready(self.take());
+        }
+        let waker = unsafe { &mut *header.waker.get() };
+        match waker {
+            Some(waker) if waker.will_wake(cx.waker()) => {}
+            _ => *waker = Some(cx.waker().clone()),
+        }
+        // the sender may have completed meanwhile, leaving the wake to us
+        if header.state.fetch_and(!REGISTERING, Ordering::AcqRel) & READY != 0 {
+            return Poll::Ready(self.take());
+        }
+        Poll::Pending
+    }
+}
+
+impl<T> Drop for SlotRx<T> {
+    #[inline]
+    fn drop(&mut self) {
+        unsafe {
+            self.header.as_ref().state.fetch_or(CLOSED, Ordering::AcqRel);
+            release(self.header);
+        }
+    }
+}
+
+/// An item and its caller's result slot, sharing one block
+#[repr(C)]
+struct Cell<F, S, E> {
+    header: Header<Result<S, E>>,
+    call: ManuallyDrop<Call<F, SlotTx<Result<S, E>>>>,
+}
+
+/// The queue's end of a pooled block, runs the item
+pub(crate) struct PoolItem<E> {
+    call: NonNull<dyn QueueItem<E> + Send>,
+    block: NonNull<u8>,
+    release: unsafe fn(NonNull<u8>),
+}
+
+// owns the item, which is `Send`, and a hold on the block
+unsafe impl<E> Send for PoolItem<E> {}
+
+impl<E> PoolItem<E> {
+    /// Put `call` and its result slot in a block of `pool`
+    #[inline]
+    pub fn new<F, S>(pool: &Arc<Pool>, call: F, gate: Option<StartGate>) -> (SlotRx<Result<S, E>>, PoolItem<E>)
+    where
+        F: Future<Output = Result<S, E>> + Send + 'static,
+        S: 'static + Sync + Send,
+        E: From<AQueueError> + Send + Sync + 'static,
+    {
+        let layout = Layout::new::<Cell<F, S, E>>();
+        let cell = pool.alloc(layout).cast::<Cell<F, S, E>>();
+        let header = cell.cast::<Header<Result<S, E>>>();
+        unsafe {
+            ptr::write(
+                cell.as_ptr(),
+                Cell {
+                    header: Header {
+                        refs: AtomicU8::new(2),
+                        state: AtomicU8::new(0),
+                        layout,
+                        pool: ManuallyDrop::new(pool.clone()),
+                        waker: UnsafeCell::new(None),
+                        value: UnsafeCell::new(None),
+                    },
+                    call: ManuallyDrop::new(Call::new(call, SlotTx { header, done: false }, gate)),
+                },
+            );
+            let call: *mut Call<F, SlotTx<Result<S, E>>> = &mut *(*cell.as_ptr()).call;
+            (
+                SlotRx { header },
+                PoolItem {
+                    call: NonNull::new_unchecked(call as *mut (dyn QueueItem<E> + Send)),
+                    block: cell.cast(),
+                    release: release_cell::<F, S, E>,
+                },
+            )
+        }
+    }
+
+    #[inline]
+    pub fn poll_run(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), E>> {
+        // Safety: the call lives in the block until `release`, it never moves
+        unsafe { Pin::new_unchecked(self.call.as_mut()) }.poll_run(cx)
+    }
+}
+
+impl<E> Drop for PoolItem<E> {
+    #[inline]
+    fn drop(&mut self) {
+        unsafe { (self.release)(self.block) }
+    }
+}
+
+/// drop the item, then the queue's hold on the block
+unsafe fn release_cell<F, S, E>(block: NonNull<u8>) {
+    let cell = block.cast::<Cell<F, S, E>>().as_ptr();
+    ManuallyDrop::drop(&mut (*cell).call);
+    // through the block's pointer, a shared borrow of the header can't free it
+    release(NonNull::new_unchecked(ptr::addr_of_mut!((*cell).header)));
+}
@@ -0,0 +1,435 @@
+#[cfg(loom)]
+use loom::sync::atomic::{AtomicU8, Ordering};
+#[cfg(not(loom))]
+use core::sync::atomic::{AtomicU8, Ordering};
+
+// One word, so whether someone runs the queue and whether it has work it hasn't seen
+// change together: a push sets NOTIFIED after its item is in, and the running side only leaves
+// by swapping a bare RUNNING for IDLE. A push landing after its last look fails that swap
+// and sends it back to the lanes; one landing after the swap finds the queue idle,
+// and its caller takes the running side (or leaves the item to the next one, as for `post`).
+const IDLE: u8 = 0;
+// someone is running the queue
+const RUNNING: u8 = 1;
+// an item was pushed, or the queue resumed, since the running side last looked
+const NOTIFIED: u8 = 2;
+
+/// Who holds the running side of a queue, and whether there is news for it
+pub(crate) struct RunState(AtomicU8);
+
+impl RunState {
+    #[inline]
+    pub fn new() -> RunState {
+        RunState(AtomicU8::new(IDLE))
+    }
+
+    /// someone holds the running side
+    #[inline]
+    pub fn is_running(&self) -> bool {
+        self.0.load(Ordering::SeqCst) & RUNNING != 0
+    }
+
+    /// take the running side if nobody holds it, whatever was notified is ours to look at now
+    #[inline]
+    pub fn acquire(&self) -> bool {
+        self.0
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |state| if state & RUNNING == 0 { Some(RUNNING) } else { None })
+            .is_ok()
+    }
+
+    /// tell the running side there is something new, called once the item is in the lanes
+    #[inline]
+    pub fn notify(&self) {
+        self.0.fetch_or(NOTIFIED, Ordering::SeqCst);
+    }
+
+    /// running side: give it up if nothing was notified since it was taken or last looked at,
+    /// otherwise take the notification and stay
+    #[inline]
+    pub fn leave(&self) -> bool {
+        match self.0.compare_exchange(RUNNING, IDLE, Ordering::SeqCst, Ordering::SeqCst) {
+            Ok(_) => true,
+            Err(_) => {
+                self.0.fetch_and(!NOTIFIED, Ordering::SeqCst);
+                false
+            }
+        }
+    }
+
+    /// running side: give it up with the work still there, the notification stays for whoever takes over
+    #[inline]
+    pub fn release(&self) {
+        self.0.fetch_and(!RUNNING, Ordering::SeqCst);
+    }
+}
+
+/// The parts of a queue the running side deals with, so `Hold` drives `AQueue` and the loom models alike
+pub(crate) trait RunQueue {
+    type Item;
+
+    fn run_state(&self) -> &RunState;
+
+    fn is_paused(&self) -> bool;
+
+    /// nothing in the lanes
+    fn is_empty(&self) -> bool;
+
+    fn pop(&self) -> Option<Self::Item>;
+
+    /// something is left for whoever takes over: an item stashed in flight, or queued ones while not paused
+    fn has_work(&self) -> bool;
+
+    /// the running side let go with work left: wake a waiting caller, start a task if the queue has a spawner
+    fn hand_over(&self);
+
+    /// the running side let go
+    fn left(&self);
+}
+
+/// The running side of a queue, taken by `Hold::take` and let go when dropped,
+/// cancelled or unwinding included, handing the work left over
+pub(crate) struct Hold<'a, Q: RunQueue> {
+    queue: &'a Q,
+    // false once `leave` gave the running side up
+    held: bool,
+    // items taken this turn
+    used: usize,
+    spent: bool,
+}
+
+impl<'a, Q: RunQueue> Hold<'a, Q> {
+    /// take the running side if nobody holds it
+    #[inline]
+    pub fn take(queue: &'a Q) -> Option<Hold<'a, Q>> {
+        queue.run_state().acquire().then(|| Hold { queue, held: true, used: 0, spent: false })
+    }
+
+    /// The next item to run, `None` once the turn is over: the lanes came up empty (or paused)
+    /// with nothing notified, or `over_budget` said so with items still queued, see `spent`
+    #[inline]
+    pub fn next(&mut self, mut over_budget: impl FnMut(usize) -> bool) -> Option<Q::Item> {
+        loop {
+            let item = if self.queue.is_paused() {
+                None
+            } else if over_budget(self.used) && !self.queue.is_empty() {
+                self.spent = true;
+                return None;
+            } else {
+                self.used += 1;
+                self.queue.pop()
+            };
+            match item {
+                Some(item) => return Some(item),
+                None if self.leave() => return None,
+                // pushed or resumed since we looked, look again
+                None => continue,
+            }
+        }
+    }
+
+    #[inline]
+    pub fn queue(&self) -> &'a Q {
+        self.queue
+    }
+
+    /// the turn ended on the budget, the running side should yield before taking the queue again
+    #[inline]
+    pub fn spent(&self) -> bool {
+        self.spent
+    }
+
+    /// give up the running side unless something was notified, see `RunState::leave`
+    #[inline]
+    fn leave(&mut self) -> bool {
+        self.held = !self.queue.run_state().leave();
+        !self.held
+    }
+}
+
+impl<Q: RunQueue> Drop for Hold<'_, Q> {
+    #[inline]
+    fn drop(&mut self) {
+        if self.held {
+            self.queue.run_state().release();
+        }
+        if self.queue.has_work() {
+            self.queue.hand_over();
+        }
+        self.queue.left();
+    }
+}
+
+/// Models of the protocol, checked over every interleaving with loom:
+/// `RUSTFLAGS="--cfg loom" cargo test --lib --release loom`.
+/// They run `Hold` itself, as `AQueue::run_ing` does, over lanes down to a count.
+#[cfg(all(test, loom))]
+mod model {
+    use super::{Hold, RunQueue, RunState};
+    use loom::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use loom::sync::Arc;
+    use loom::thread;
+
+    /// the parts of a queue the protocol is about: the lanes and the stash down to counts,
+    /// the pause flag, the drain budget, and the tasks started on a spawner, if it has one
+    struct Queue {
+        state: RunState,
+        items: AtomicUsize,
+        stashed: AtomicUsize,
+        paused: AtomicBool,
+        budget: Option<usize>,
+        spawner: bool,
+        spawned: AtomicUsize,
+        ran: AtomicUsize,
+    }
+
+    impl RunQueue for Queue {
+        type Item = ();
+
+        fn run_state(&self) -> &RunState {
+            &self.state
+        }
+
+        fn is_paused(&self) -> bool {
+            self.paused.load(Ordering::SeqCst)
+        }
+
+        fn is_empty(&self) -> bool {
+            self.items.load(Ordering::SeqCst) == 0
+        }
+
+        fn pop(&self) -> Option<()> {
+            self.items.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1)).ok().map(|_| ())
+        }
+
+        fn has_work(&self) -> bool {
+            self.stashed.load(Ordering::SeqCst) != 0 || (!self.is_empty() && !self.is_paused())
+        }
+
+        fn hand_over(&self) {
+            self.spawn_run_ing();
+        }
+
+        fn left(&self) {}
+    }
+
+    impl Queue {
+        fn new(items: usize, paused: bool) -> Queue {
+            Queue {
+                state: RunState::new(),
+                items: AtomicUsize::new(items),
+                stashed: AtomicUsize::new(0),
+                paused: AtomicBool::new(paused),
+                budget: None,
+                spawner: false,
+                spawned: AtomicUsize::new(0),
+                ran: AtomicUsize::new(0),
+            }
+        }
+
+        fn spawner(self) -> Queue {
+            Queue { spawner: true, ..self }
+        }
+
+        fn budget(self, budget: usize) -> Queue {
+            Queue { budget: Some(budget), ..self }
+        }
+
+        /// `AQueue::try_push_item`
+        fn push(&self) {
+            self.items.fetch_add(1, Ordering::SeqCst);
+            self.state.notify();
+        }
+
+        /// `AQueue::post`
+        fn post(&self) {
+            self.push();
+            self.spawn_run_ing();
+        }
+
+        /// `AQueue::spawn_run_ing`, the task counted here runs in `check`
+        fn spawn_run_ing(&self) {
+            if self.spawner && !self.state.is_running() && !self.is_paused() {
+                self.spawned.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        /// one turn of `AQueue::run_ing`, its future dropped mid-item after `cancel_after` items if given;
+        /// true if the turn ended on the budget and `run_ing` would go on after yielding
+        fn turn(&self, cancel_after: Option<usize>) -> bool {
+            let Some(mut hold) = Hold::take(self) else {
+                return false;
+            };
+            // `Running::current`
+            let mut current = self.stashed.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1)).is_ok();
+            let mut ran = 0;
+            loop {
+                if !current && hold.next(|used| matches!(self.budget, Some(budget) if used >= budget)).is_none() {
+                    break;
+                }
+                if cancel_after == Some(ran) {
+                    // `Running::drop`, then the hold's
+                    self.stashed.fetch_add(1, Ordering::SeqCst);
+                    return false;
+                }
+                self.ran.fetch_add(1, Ordering::SeqCst);
+                ran += 1;
+                current = false;
+            }
+            hold.spent()
+        }
+
+        fn run_ing(&self) {
+            while self.turn(None) {}
+        }
+
+        /// `AQueue::resume`, without the waiting callers
+        fn resume(&self) {
+            self.paused.store(false, Ordering::SeqCst);
+            self.state.notify();
+            if !self.is_empty() {
+                self.spawn_run_ing();
+            }
+        }
+
+        /// nothing stranded: once the tasks started on the spawner ran,
+        /// every item ran and nobody holds the queue
+        fn check(&self, pushed: usize) {
+            while self.spawned.load(Ordering::SeqCst) != 0 {
+                self.spawned.fetch_sub(1, Ordering::SeqCst);
+                self.run_ing();
+            }
+            assert_eq!(self.ran.load(Ordering::SeqCst), pushed);
+            assert_eq!(self.items.load(Ordering::SeqCst), 0);
+            assert_eq!(self.stashed.load(Ordering::SeqCst), 0);
+            assert!(!self.state.is_running());
+        }
+    }
+
+    /// a `run` caller pushing while the running side finds the lanes empty
+    #[test]
+    fn loom_push_while_leaving() {
+        loom::model(|| {
+            let queue = Arc::new(Queue::new(1, false));
+            let runner = {
+                let queue = queue.clone();
+                thread::spawn(move || queue.run_ing())
+            };
+            queue.push();
+            queue.run_ing();
+            runner.join().unwrap();
+            queue.check(2);
+        });
+    }
+
+    /// a `post` on a queue with a spawner: the poster only starts a task if it sees nobody running,
+    /// so the running side on its way out has to pick the item up
+    #[test]
+    fn loom_post_while_leaving() {
+        loom::model(|| {
+            let queue = Arc::new(Queue::new(1, false).spawner());
+            let runner = {
+                let queue = queue.clone();
+                thread::spawn(move || queue.run_ing())
+            };
+            queue.post();
+            runner.join().unwrap();
+            queue.check(2);
+        });
+    }
+
+    /// `resume` on a queue with a spawner while the running side leaves because it is paused
+    #[test]
+    fn loom_resume_while_leaving() {
+        loom::model(|| {
+            let queue = Arc::new(Queue::new(1, true).spawner());
+            let runner = {
+                let queue = queue.clone();
+                thread::spawn(move || queue.run_ing())
+            };
+            queue.resume();
+            runner.join().unwrap();
+            queue.check(1);
+        });
+    }
+
+    /// two pushers racing each other and the running side
+    #[test]
+    fn loom_two_pushers() {
+        loom::model(|| {
+            let queue = Arc::new(Queue::new(0, false));
+            let pushers: Vec<_> = (0..2)
+                .map(|_| {
+                    let queue = queue.clone();
+                    thread::spawn(move || {
+                        queue.push();
+                        queue.run_ing();
+                    })
+                })
+                .collect();
+            queue.run_ing();
+            for pusher in pushers {
+                pusher.join().unwrap();
+            }
+            queue.check(2);
+        });
+    }
+
+    /// the running side dropped mid-item, cancelled or unwinding, with items queued and a post racing it:
+    /// only the spawner is left to pick them up
+    #[test]
+    fn loom_dropped_with_work_left() {
+        loom::model(|| {
+            let queue = Arc::new(Queue::new(2, false).spawner());
+            let runner = {
+                let queue = queue.clone();
+                thread::spawn(move || {
+                    queue.turn(Some(0));
+                })
+            };
+            queue.post();
+            runner.join().unwrap();
+            queue.check(3);
+        });
+    }
+
+    /// the budget spent with items queued and a post racing it, the running side dropped
+    /// while it yields: only the spawner is left to pick them up
+    #[test]
+    fn loom_budget_spent_with_work_left() {
+        loom::model(|| {
+            let queue = Arc::new(Queue::new(2, false).spawner().budget(1));
+            let runner = {
+                let queue = queue.clone();
+                thread::spawn(move || {
+                    queue.turn(None);
+                })
+            };
+            queue.post();
+            runner.join().unwrap();
+            queue.check(3);
+        });
+    }
+
+    /// the model catches the window: leaving with a plain release strands a post
+    #[test]
+    #[should_panic]
+    fn loom_release_without_notify_strands() {
+        loom::model(|| {
+            let queue = Arc::new(Queue::new(1, false).spawner());
+            let runner = {
+                let queue = queue.clone();
+                thread::spawn(move || {
+                    if queue.state.acquire() {
+                        while queue.pop().is_some() {
+                            queue.ran.fetch_add(1, Ordering::SeqCst);
+                        }
+                        queue.state.release();
+                    }
+                })
+            };
+            queue.post();
+            runner.join().unwrap();
+            queue.check(2);
+        });
+    }
+}
@@ -0,0 +1,217 @@
+use super::time::Instant;
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use core::time::Duration;
+
+const BUCKETS: usize = 32;
+
+// targets without 64 bit atomics count in words
+#[cfg(target_has_atomic = "64")]
+pub(super) type Counter = core::sync::atomic::AtomicU64;
+#[cfg(not(target_has_atomic = "64"))]
+pub(super) type Counter = AtomicUsize;
+
+/// Durations counted in power of two buckets of microseconds:
+/// bucket 0 holds what took under 1µs, bucket `i` what took under `2^i` µs, the last one everything above.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Histogram {
+    buckets: [u64; BUCKETS],
+}
+
+impl Histogram {
+    #[inline]
+    pub fn buckets(&self) -> &[u64] {
+        &self.buckets
+    }
+
+    /// upper bound of bucket `i`, the last bucket has none
+    #[inline]
+    pub fn upper_bound(i: usize) -> Option<Duration> {
+        if i + 1 < BUCKETS {
+            Some(Duration::from_micros(1 << i))
+        } else {
+            None
+        }
+    }
+
+    #[inline]
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    /// upper bound of the bucket holding the `q` quantile (`0.0..=1.0`),
+    /// `None` if nothing was recorded or it fell in the last bucket
+    pub fn quantile(&self, q: f64) -> Option<Duration> {
+        let count = self.count();
+        if count == 0 {
+            return None;
+        }
+        let rank = ((count as f64 * q.clamp(0.0, 1.0)).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (i, n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                return Self::upper_bound(i);
+            }
+        }
+        None
+    }
+}
+
+/// Snapshot of an `AQueue`'s counters, see `AQueue::stats`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueueStats {
+    /// items waiting to start
+    pub depth: usize,
+    /// most items ever waiting at once
+    pub max_depth: usize,
+    /// items accepted
+    pub enqueued: u64,
+    /// items that ran and succeeded
+    pub completed: u64,
+    /// items that failed, panicked, timed out or were dropped because their caller gave up
+    pub failed: u64,
+    /// time from enqueue to start, of the sampled items, see `AQueueBuilder::stats_sample`
+    pub wait_time: Histogram,
+    /// time from start to end, of the sampled items
+    pub run_time: Histogram,
+    /// calls whose item reused a pooled block, see `AQueueBuilder::pool`
+    pub pool_hits: u64,
+    /// calls whose item needed a new block, the pool being empty
+    pub pool_misses: u64,
+}
+
+struct AtomicHistogram([Counter; BUCKETS]);
+
+impl AtomicHistogram {
+    #[inline]
+    fn new() -> AtomicHistogram {
+        AtomicHistogram(core::array::from_fn(|_| Counter::new(0)))
+    }
+
+    #[inline]
+    fn record(&self, elapsed: Duration) {
+        let micros = elapsed.as_micros().min(u64::MAX as u128) as u64;
+        let i = ((u64::BITS - micros.leading_zeros()) as usize).min(BUCKETS - 1);
+        bump(&self.0[i]);
+    }
+
+    #[inline]
+    fn snapshot(&self) -> Histogram {
+        Histogram {
+            buckets: core::array::from_fn(|i| read(&self.0[i])),
+        }
+    }
+}
+
+/// Live counters. Apart from the sampling tick only the running side writes them,
+/// and there is one at a time, so a plain load and store does and the push path only pays the tick
+pub(crate) struct Stats {
+    #[cfg_attr(not(feature = "std"), allow(dead_code))]
+    sample: u32,
+    // pushes seen, every `sample`th one is timed
+    #[cfg_attr(not(feature = "std"), allow(dead_code))]
+    tick: AtomicU32,
+    popped: Counter,
+    max_depth: AtomicUsize,
+    completed: Counter,
+    failed: Counter,
+    wait_time: AtomicHistogram,
+    run_time: AtomicHistogram,
+}
+
+#[inline]
+fn bump(counter: &Counter) {
+    counter.store(counter.load(Ordering::Relaxed) + 1, Ordering::Relaxed);
+}
+
+#[cfg(target_has_atomic = "64")]
+#[inline]
+pub(super) fn read(counter: &Counter) -> u64 {
+    counter.load(Ordering::Relaxed)
+}
+
+#[cfg(not(target_has_atomic = "64"))]
+#[inline]
+pub(super) fn read(counter: &Counter) -> u64 {
+    counter.load(Ordering::Relaxed) as u64
+}
+
+impl Stats {
+    #[inline]
+    pub fn new(sample: u32) -> Stats {
+        Stats {
+            sample: sample.max(1),
+            tick: AtomicU32::new(0),
+            popped: Counter::new(0),
+            max_depth: AtomicUsize::new(0),
+            completed: Counter::new(0),
+            failed: Counter::new(0),
+            wait_time: AtomicHistogram::new(),
+            run_time: AtomicHistogram::new(),
+        }
+    }
+
+    /// push side, the time to stamp the item with if it is one of the timed ones
+    #[cfg(feature = "std")]
+    #[inline]
+    pub fn stamp(&self) -> Option<Instant> {
+        if self.sample == 1 {
+            return Some(Instant::now());
+        }
+        let n = self.tick.fetch_add(1, Ordering::Relaxed).wrapping_add(1);
+        if n.is_multiple_of(self.sample) {
+            Some(Instant::now())
+        } else {
+            None
+        }
+    }
+
+    /// without a clock nothing is timed
+    #[cfg(not(feature = "std"))]
+    #[inline]
+    pub fn stamp(&self) -> Option<Instant> {
+        None
+    }
+
+    /// running side, `depth` counts the popped item
+    #[inline]
+    pub fn popped(&self, depth: usize) {
+        bump(&self.popped);
+        if depth > self.max_depth.load(Ordering::Relaxed) {
+            self.max_depth.store(depth, Ordering::Relaxed);
+        }
+    }
+
+    #[inline]
+    pub fn wait(&self, wait: Duration) {
+        self.wait_time.record(wait);
+    }
+
+    #[inline]
+    pub fn ran(&self, run: Option<Duration>, ok: bool) {
+        if let Some(run) = run {
+            self.run_time.record(run);
+        }
+        if ok {
+            bump(&self.completed);
+        } else {
+            bump(&self.failed);
+        }
+    }
+
+    #[inline]
+    pub fn snapshot(&self, depth: usize, (pool_hits, pool_misses): (u64, u64)) -> QueueStats {
+        // depth only drops by popping, so its peak was either just before a pop or is now
+        QueueStats {
+            depth,
+            max_depth: self.max_depth.load(Ordering::Relaxed).max(depth),
+            enqueued: read(&self.popped) + depth as u64,
+            completed: read(&self.completed),
+            failed: read(&self.failed),
+            wait_time: self.wait_time.snapshot(),
+            run_time: self.run_time.snapshot(),
+            pool_hits,
+            pool_misses,
+        }
+    }
+}
@@ -0,0 +1,37 @@
+/// The clock behind wait/run times and deadlines
+#[cfg(feature = "std")]
+pub(crate) use std::time::Instant;
+
+/// Without `std` there is no clock: no instant is ever taken, so nothing is timed
+#[cfg(not(feature = "std"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Instant {}
+
+#[cfg(not(feature = "std"))]
+impl Instant {
+    #[inline]
+    pub fn elapsed(&self) -> core::time::Duration {
+        match *self {}
+    }
+
+    #[inline]
+    pub fn saturating_duration_since(&self, _earlier: Instant) -> core::time::Duration {
+        match *self {}
+    }
+}
+
+/// the current time, `None` without a clock
+#[inline]
+pub(crate) fn now() -> Option<Instant> {
+    #[cfg(feature = "std")]
+    return Some(Instant::now());
+    #[cfg(not(feature = "std"))]
+    None
+}
+
+/// true once `deadline` is reached
+#[inline]
+pub(crate) fn passed(deadline: Instant) -> bool {
+    matches!(now(), Some(now) if now >= deadline)
+}
+
@@ -0,0 +1,59 @@
+use crate::runtime::SleepFuture;
+use crate::{AQueueError, Timer};
+use anyhow::Result;
+use futures_timer::Delay;
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use core::time::Duration;
+
+/// Sleep on `timer`, or on `futures-timer` without one
+#[inline]
+pub(crate) fn sleep(timer: Option<&dyn Timer>, duration: Duration) -> SleepFuture {
+    match timer {
+        Some(timer) => timer.sleep(duration),
+        None => Box::pin(Delay::new(duration)),
+    }
+}
+
+/// Runs the call, but gives up with `AQueueError::ExecTimeout` once `timeout` has passed since the first poll
+pub(crate) struct ExecTimeout<F> {
+    fut: F,
+    timeout: Option<Duration>,
+    timer: Option<Arc<dyn Timer>>,
+    delay: Option<SleepFuture>,
+}
+
+impl<F> ExecTimeout<F> {
+    /// without a `timeout` it just runs `fut`
+    #[inline]
+    pub fn new(fut: F, timeout: Option<Duration>, timer: Option<Arc<dyn Timer>>) -> ExecTimeout<F> {
+        ExecTimeout { fut, timeout, timer, delay: None }
+    }
+}
+
+impl<F, S, E> Future for ExecTimeout<F>
+where
+    F: Future<Output = Result<S, E>>,
+    E: From<AQueueError>,
+{
+    type Output = Result<S, E>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // Safety: `fut` is never moved out of the pinned `ExecTimeout`
+        let this = unsafe { self.get_unchecked_mut() };
+        if let Poll::Ready(r) = unsafe { Pin::new_unchecked(&mut this.fut) }.poll(cx) {
+            return Poll::Ready(r);
+        }
+        if let Some(timeout) = this.timeout.take() {
+            // created here so the clock starts when the item starts, not when it is queued
+            this.delay = Some(sleep(this.timer.as_deref(), timeout));
+        }
+        match this.delay.as_mut().map(|delay| delay.as_mut().poll(cx)) {
+            Some(Poll::Ready(())) => Poll::Ready(Err(AQueueError::ExecTimeout.into())),
+            _ => Poll::Pending,
+        }
+    }
+}
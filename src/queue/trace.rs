@@ -0,0 +1,57 @@
+/// The caller's span, taken when an item is made so the item runs inside it
+/// whichever task ends up running the queue. Nothing without the `tracing` feature.
+#[cfg(feature = "tracing")]
+pub(crate) struct ItemSpan(tracing::Span);
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) struct ItemSpan;
+
+#[cfg(feature = "tracing")]
+impl ItemSpan {
+    #[inline]
+    pub fn current() -> ItemSpan {
+        ItemSpan(tracing::Span::current())
+    }
+
+    /// run one poll of the item inside the span
+    #[inline]
+    pub fn in_scope<T>(&self, f: impl FnOnce() -> T) -> T {
+        self.0.in_scope(f)
+    }
+
+    #[inline]
+    pub fn started(&self) {
+        self.0.in_scope(|| tracing::trace!(target: "aqueue", "item started"));
+    }
+
+    #[inline]
+    pub fn finished(&self, ok: bool) {
+        self.0.in_scope(|| tracing::trace!(target: "aqueue", ok, "item finished"));
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+impl ItemSpan {
+    #[inline]
+    pub fn current() -> ItemSpan {
+        ItemSpan
+    }
+
+    #[inline]
+    pub fn in_scope<T>(&self, f: impl FnOnce() -> T) -> T {
+        f()
+    }
+
+    #[inline]
+    pub fn started(&self) {}
+
+    #[inline]
+    pub fn finished(&self, _ok: bool) {}
+}
+
+/// the item is in, logged in the caller's own context
+#[inline]
+pub(crate) fn enqueued(_priority: super::Priority, _depth: usize) {
+    #[cfg(feature = "tracing")]
+    tracing::trace!(target: "aqueue", priority = ?_priority, depth = _depth, "item enqueued");
+}
@@ -0,0 +1,150 @@
+use alloc::boxed::Box;
+use core::future::Future;
+use core::pin::Pin;
+use core::time::Duration;
+
+pub type SpawnFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+pub type SleepFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Lets the queue start a task on the user's runtime, e.g. to run items nobody is waiting for
+///
+/// Any `Fn(SpawnFuture)` works:
+/// ```ignore
+/// AQueue::builder().spawner(|fut: SpawnFuture| { tokio::spawn(fut); })
+/// ```
+pub trait Spawner: Send + Sync {
+    fn spawn(&self, fut: SpawnFuture);
+}
+
+impl<F> Spawner for F
+where
+    F: Fn(SpawnFuture) + Send + Sync,
+{
+    #[inline]
+    fn spawn(&self, fut: SpawnFuture) {
+        self(fut)
+    }
+}
+
+/// Lets the queue wait on the user's runtime clock, for the `run_timeout` deadlines.
+/// Without one they run on `futures-timer`'s own thread.
+///
+/// Any `Fn(Duration) -> SleepFuture` works:
+/// ```ignore
+/// AQueue::builder().timer(|d| -> SleepFuture { Box::pin(tokio::time::sleep(d)) })
+/// ```
+pub trait Timer: Send + Sync {
+    fn sleep(&self, duration: Duration) -> SleepFuture;
+}
+
+impl<F> Timer for F
+where
+    F: Fn(Duration) -> SleepFuture + Send + Sync,
+{
+    #[inline]
+    fn sleep(&self, duration: Duration) -> SleepFuture {
+        self(duration)
+    }
+}
+
+/// A tokio runtime: spawns on it and sleeps on its timer, from whatever thread the queue is used,
+/// blocking calls on a `std::thread` and posts from outside the runtime included.
+///
+/// ```ignore
+/// let queue = AQueue::builder().runtime(Tokio::current()).build();
+/// ```
+#[cfg(feature = "rt-tokio")]
+#[derive(Debug, Clone)]
+pub struct Tokio(tokio::runtime::Handle);
+
+#[cfg(feature = "rt-tokio")]
+impl Tokio {
+    /// The runtime of the caller
+    ///
+    /// # Panics
+    ///
+    /// If called from outside a tokio runtime.
+    #[inline]
+    pub fn current() -> Tokio {
+        Tokio(tokio::runtime::Handle::current())
+    }
+}
+
+#[cfg(feature = "rt-tokio")]
+impl From<tokio::runtime::Handle> for Tokio {
+    #[inline]
+    fn from(handle: tokio::runtime::Handle) -> Tokio {
+        Tokio(handle)
+    }
+}
+
+#[cfg(feature = "rt-tokio")]
+impl Spawner for Tokio {
+    #[inline]
+    fn spawn(&self, fut: SpawnFuture) {
+        self.0.spawn(fut);
+    }
+}
+
+#[cfg(feature = "rt-tokio")]
+impl Timer for Tokio {
+    #[inline]
+    fn sleep(&self, duration: Duration) -> SleepFuture {
+        // the sleep takes the timer of the current runtime when made, polling it needs none
+        let _enter = self.0.enter();
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+/// The async-std runtime: spawns on its global executor and sleeps on its timer, usable from any thread
+///
+/// ```ignore
+/// let queue = AQueue::builder().runtime(AsyncStd).build();
+/// ```
+#[cfg(feature = "rt-async-std")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AsyncStd;
+
+#[cfg(feature = "rt-async-std")]
+impl Spawner for AsyncStd {
+    #[inline]
+    fn spawn(&self, fut: SpawnFuture) {
+        // dropping the handle detaches the task
+        async_std::task::spawn(fut);
+    }
+}
+
+#[cfg(feature = "rt-async-std")]
+impl Timer for AsyncStd {
+    #[inline]
+    fn sleep(&self, duration: Duration) -> SleepFuture {
+        Box::pin(async_std::task::sleep(duration))
+    }
+}
+
+/// The smol runtime: spawns on its global executor and sleeps on its timer, usable from any thread
+///
+/// ```ignore
+/// let queue = AQueue::builder().runtime(Smol).build();
+/// ```
+#[cfg(feature = "rt-smol")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Smol;
+
+#[cfg(feature = "rt-smol")]
+impl Spawner for Smol {
+    #[inline]
+    fn spawn(&self, fut: SpawnFuture) {
+        smol::spawn(fut).detach();
+    }
+}
+
+#[cfg(feature = "rt-smol")]
+impl Timer for Smol {
+    #[inline]
+    fn sleep(&self, duration: Duration) -> SleepFuture {
+        Box::pin(async move {
+            smol::Timer::after(duration).await;
+        })
+    }
+}
@@ -1,4 +1,6 @@
-#![feature(async_closure)]
+#![cfg(feature = "std")]
+// `cargo +nightly miri test --test test` checks the pool, slot and turn code;
+// tests with long loops or that lean on wall-clock timing are left out of it
 
 use aqueue::AQueue;
 use std::sync::Arc;
@@ -9,6 +11,7 @@ use anyhow::*;
 static mut VALUE: u64 = 0;
 
 #[tokio::test]
+#[cfg_attr(miri, ignore)]
 async fn test() -> Result<(), Box<dyn Error>> {
     let queue = Arc::new(AQueue::new());
 
@@ -92,6 +95,7 @@ use tokio::task::JoinHandle;
 
 
 #[tokio::test]
+#[cfg_attr(miri, ignore)]
 async fn test_struct() -> Result<(), Box<dyn Error>> {
     #[async_trait]
     pub trait IFoo {
@@ -184,6 +188,7 @@ async fn test_struct() -> Result<(), Box<dyn Error>> {
 }
 
 #[tokio::test]
+#[cfg_attr(miri, ignore)]
 async fn test_count() -> Result<(), Box<dyn Error>> {
     struct Foo {
         count: u64,
@@ -257,6 +262,7 @@ async fn test_count() -> Result<(), Box<dyn Error>> {
 }
 
 #[tokio::test]
+#[cfg_attr(miri, ignore)]
 async fn test_actor() -> Result<(), Box<dyn Error>> {
     #[derive(Default)]
     struct Foo {
@@ -297,9 +303,7 @@ async fn test_actor() -> Result<(), Box<dyn Error>> {
         }
 
         async fn get_len<'a>(&'a self,b:&'a [u8])->Result<usize>{
-            unsafe {
-                self.inner_call_ref(async move |_| Ok(b.len())).await
-            }
+            self.inner_call_ref(async move |_| Ok(b.len())).await
         }
     }
 
@@ -322,7 +326,7 @@ async fn test_actor() -> Result<(), Box<dyn Error>> {
 
 
 
-    let buff=vec![1,2,3,4,5];
+    let buff=[1,2,3,4,5];
     let x={
 
         a_foo.get_len(&buff[..])
@@ -332,3 +336,1652 @@ async fn test_actor() -> Result<(), Box<dyn Error>> {
 
     Ok(())
 }
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[cfg_attr(miri, ignore)]
+async fn test_multi_thread() -> Result<(), Box<dyn Error>> {
+    let actor = Arc::new(Actor::new(0u64));
+    let start = Instant::now();
+    let mut vec = vec![];
+    for _ in 0..64 {
+        let a_actor = actor.clone();
+        vec.push(tokio::spawn(async move {
+            for _ in 0..31250 {
+                a_actor
+                    .inner_call(async move |inner| {
+                        *inner.get_mut() += 1;
+                        Ok(())
+                    })
+                    .await
+                    .unwrap();
+            }
+        }));
+    }
+    for j in vec {
+        j.await?;
+    }
+    let v = actor.inner_call(async move |inner| Ok(*inner.get())).await?;
+    println!("{} {}", start.elapsed().as_secs_f32(), v);
+    assert_eq!(v, 2000000);
+    Ok(())
+}
+
+#[tokio::test]
+#[cfg_attr(miri, ignore)]
+async fn test_stats() -> Result<()> {
+    let actor = Arc::new(Actor::with_queue(0u64, AQueue::builder().stats_sample(1).build()));
+    let stats = actor.stats();
+    assert_eq!((stats.depth, stats.enqueued, stats.wait_time.count()), (0, 0, 0));
+
+    let mut calls = Vec::new();
+    for i in 0..10u64 {
+        let actor = actor.clone();
+        calls.push(tokio::spawn(async move {
+            actor
+                .inner_call(async move |inner| {
+                    sleep(Duration::from_millis(2)).await;
+                    if i % 5 == 4 {
+                        bail!("bad {}", i)
+                    }
+                    *inner.get_mut() += 1;
+                    Ok(())
+                })
+                .await
+        }));
+    }
+    sleep(Duration::from_millis(1)).await;
+    assert!(actor.stats().depth > 0);
+    for call in calls {
+        let _ = call.await?;
+    }
+
+    let stats = actor.stats();
+    assert_eq!(stats.depth, 0);
+    assert!(stats.max_depth >= 5, "{:?}", stats);
+    assert_eq!(stats.enqueued, 10);
+    assert_eq!(stats.completed, 8);
+    assert_eq!(stats.failed, 2);
+    assert_eq!(stats.wait_time.count(), 10);
+    assert_eq!(stats.run_time.count(), 10);
+    // every call slept 2ms
+    assert!(stats.run_time.quantile(0.0).unwrap() >= Duration::from_millis(2));
+    assert!(stats.wait_time.quantile(1.0).unwrap() >= Duration::from_millis(10));
+
+    // by default only some items are timed, the counters still see all of them
+    let queue = AQueue::new();
+    for i in 0..100 {
+        queue.run(async move |x| Ok(x), i).await?;
+    }
+    let stats = queue.stats();
+    assert_eq!((stats.enqueued, stats.completed, stats.max_depth), (100, 100, 1));
+    assert!(stats.run_time.count() > 0 && stats.run_time.count() < 100);
+
+    // each queue samples its own pushes, whatever else the thread pushes to
+    let a = AQueue::builder().stats_sample(2).build();
+    let b = AQueue::builder().stats_sample(2).build();
+    for i in 0..10 {
+        a.run(async move |x| Ok(x), i).await?;
+        b.run(async move |x| Ok(x), i).await?;
+    }
+    assert_eq!(a.stats().run_time.count(), 5);
+    assert_eq!(b.stats().run_time.count(), 5);
+    Ok(())
+}
+
+#[cfg(feature = "tracing")]
+#[tokio::test]
+async fn test_tracing() -> Result<()> {
+    use std::sync::Mutex;
+    use tracing::Instrument;
+    use tracing_subscriber::layer::{Context, SubscriberExt};
+
+    // records each event with the name of the span it happened in
+    #[derive(Clone, Default)]
+    struct Events(Arc<Mutex<Vec<(String, String)>>>);
+
+    impl<S> tracing_subscriber::Layer<S> for Events
+    where
+        S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+    {
+        fn on_event(&self, event: &tracing::Event<'_>, ctx: Context<'_, S>) {
+            struct Message(String);
+            impl tracing::field::Visit for Message {
+                fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+                    if field.name() == "message" {
+                        self.0 = format!("{:?}", value);
+                    }
+                }
+            }
+            let mut message = Message(String::new());
+            event.record(&mut message);
+            let span = ctx.event_span(event).map(|span| span.name().to_string()).unwrap_or_default();
+            self.0.lock().unwrap().push((message.0, span));
+        }
+    }
+
+    let events = Events::default();
+    let _guard = tracing::subscriber::set_default(tracing_subscriber::registry().with(events.clone()));
+
+    let queue = Arc::new(AQueue::new());
+    // req_a holds the queue, so req_b's item runs on req_a's task
+    let a = {
+        let queue = queue.clone();
+        tokio::spawn(
+            async move {
+                queue
+                    .run(
+                        async move |_| {
+                            sleep(Duration::from_millis(20)).await;
+                            Ok(())
+                        },
+                        (),
+                    )
+                    .await
+            }
+            .instrument(tracing::info_span!("req_a")),
+        )
+    };
+    sleep(Duration::from_millis(5)).await;
+    queue
+        .run(
+            async move |_| {
+                tracing::info!("inside b");
+                Ok(())
+            },
+            (),
+        )
+        .instrument(tracing::info_span!("req_b"))
+        .await?;
+    a.await??;
+
+    let events = events.0.lock().unwrap().clone();
+    let b: Vec<_> = events.iter().filter(|(_, span)| span == "req_b").map(|(message, _)| message.as_str()).collect();
+    assert_eq!(b, vec!["item enqueued", "item started", "inside b", "item finished"]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_error_kinds() -> Result<()> {
+    use aqueue::{AQueueError, AQueueItem, QueueItem};
+
+    // an item runs once, the second run is told apart from a business error
+    let (rx, item) = AQueueItem::<_, _, anyhow::Error>::new(async move { Ok(1) });
+    let mut item = std::pin::pin!(item);
+    item.as_mut().run().await?;
+    let err = item.as_mut().run().await.unwrap_err();
+    assert_eq!(err.downcast_ref::<AQueueError>(), Some(&AQueueError::AlreadyRun));
+    assert_eq!(rx.await.ok().unwrap()?, 1);
+
+    // nobody is left to take the result
+    let (rx, item) = AQueueItem::<_, _, anyhow::Error>::new(async move { Ok(1) });
+    drop(rx);
+    let err = std::pin::pin!(item).run().await.unwrap_err();
+    assert_eq!(err.downcast_ref::<AQueueError>(), Some(&AQueueError::ReceiverDropped));
+
+    // the caller's own errors stay theirs
+    let queue = AQueue::new();
+    let err = queue.run(async move |_| -> Result<()> { bail!("mine") }, ()).await.unwrap_err();
+    assert!(err.downcast_ref::<AQueueError>().is_none());
+    assert_eq!(AQueueError::Full.to_string(), "queue is full");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_typed_error() {
+    use aqueue::{AQueueBuilder, AQueueError};
+    use std::sync::Mutex;
+
+    #[derive(Debug, PartialEq)]
+    enum DbError {
+        Queue(AQueueError),
+        NotFound(u32),
+    }
+
+    impl From<AQueueError> for DbError {
+        fn from(err: AQueueError) -> Self {
+            DbError::Queue(err)
+        }
+    }
+
+    let actor: Actor<Vec<u32>, DbError> = Actor::with_queue(vec![1, 2], AQueue::default());
+    let found = actor
+        .inner_call(async move |inner| inner.get().iter().copied().find(|x| *x == 2).ok_or(DbError::NotFound(2)))
+        .await;
+    assert_eq!(found, Ok(2));
+    let found = actor
+        .inner_call(async move |inner| inner.get().iter().copied().find(|x| *x == 3).ok_or(DbError::NotFound(3)))
+        .await;
+    assert_eq!(found, Err(DbError::NotFound(3)));
+
+    // queue failures come as the caller's own type
+    let r = actor
+        .inner_call(async move |_| -> Result<(), DbError> { panic!("boom") })
+        .await;
+    assert_eq!(r, Err(DbError::Queue(AQueueError::Panicked("boom".to_string()))));
+    assert!(actor.is_poisoned());
+    actor.close();
+    assert_eq!(actor.try_inner_call(async move |_| Ok(())).await, Err(DbError::Queue(AQueueError::Closed)));
+
+    // the error sink sees the typed error too
+    let sunk = Arc::new(Mutex::new(Vec::new()));
+    let queue = {
+        let sunk = sunk.clone();
+        Arc::new(
+            AQueueBuilder::<DbError>::default()
+                .error_sink(move |err| sunk.lock().unwrap().push(err))
+                .build(),
+        )
+    };
+    queue.post(async move |id: u32| -> Result<(), DbError> { Err(DbError::NotFound(id)) }, 7).unwrap();
+    queue.drain().await;
+    assert_eq!(*sunk.lock().unwrap(), vec![DbError::NotFound(7)]);
+}
+
+#[tokio::test]
+#[cfg_attr(miri, ignore)]
+async fn test_drain_budget() -> Result<()> {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    // items that never await would keep the runtime thread until all are done,
+    // the budget makes the running side yield so the watcher gets to look in between
+    async fn run(queue: AQueue) -> Result<(u32, u32)> {
+        let queue = Arc::new(queue);
+        let count = Arc::new(AtomicU32::new(0));
+        for _ in 0..100 {
+            queue.post(
+                async move |count: Arc<AtomicU32>| {
+                    std::thread::sleep(Duration::from_micros(100));
+                    count.fetch_add(1, Ordering::Relaxed);
+                    Ok(())
+                },
+                count.clone(),
+            )?;
+        }
+        let watcher = {
+            let count = count.clone();
+            tokio::spawn(async move { count.load(Ordering::Relaxed) })
+        };
+        queue.drain().await;
+        Ok((watcher.await?, count.load(Ordering::Relaxed)))
+    }
+
+    assert_eq!(run(AQueue::new()).await?, (100, 100));
+    assert_eq!(run(AQueue::builder().drain_budget(10).build()).await?, (10, 100));
+    let (seen, done) = run(AQueue::builder().drain_time(Duration::from_millis(2)).build()).await?;
+    assert!(seen < 100 && done == 100, "{}", seen);
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_blocking() -> Result<()> {
+    use aqueue::AQueueError;
+
+    let actor = Arc::new(Actor::new(0u64));
+    let threads: Vec<_> = (0..4)
+        .map(|_| {
+            let actor = actor.clone();
+            std::thread::spawn(move || {
+                for _ in 0..1000 {
+                    actor.inner_call_blocking(async move |inner| {
+                        *inner.get_mut() += 1;
+                        Ok(())
+                    })?;
+                }
+                Ok::<_, anyhow::Error>(())
+            })
+        })
+        .collect();
+    for _ in 0..1000 {
+        actor
+            .inner_call(async move |inner| {
+                *inner.get_mut() += 1;
+                Ok(())
+            })
+            .await?;
+    }
+    for thread in threads {
+        thread.join().unwrap()?;
+    }
+    assert_eq!(actor.inner_call(async move |inner| Ok(*inner.get())).await?, 5000);
+
+    // items using the runtime: on a driven queue the threads only start the driver task,
+    // through a spawner holding the runtime's handle, and every item runs on the runtime
+    let handle = tokio::runtime::Handle::current();
+    let driven = Arc::new(Actor::with_queue(
+        0u64,
+        AQueue::builder().build_driven(move |fut: aqueue::runtime::SpawnFuture| {
+            handle.spawn(fut);
+        }),
+    ));
+    let threads: Vec<_> = (0..2)
+        .map(|_| {
+            let driven = driven.clone();
+            std::thread::spawn(move || {
+                for _ in 0..50 {
+                    driven.inner_call_blocking(async move |inner| {
+                        sleep(Duration::from_micros(100)).await;
+                        *inner.get_mut() += 1;
+                        Ok(())
+                    })?;
+                }
+                Ok::<_, anyhow::Error>(())
+            })
+        })
+        .collect();
+    // the threads find the queue idle first
+    sleep(Duration::from_millis(5)).await;
+    for _ in 0..50 {
+        driven
+            .inner_call(async move |inner| {
+                sleep(Duration::from_micros(100)).await;
+                *inner.get_mut() += 1;
+                Ok(())
+            })
+            .await?;
+    }
+    for thread in threads {
+        thread.join().unwrap()?;
+    }
+    assert_eq!(driven.inner_call(async move |inner| Ok(*inner.get())).await?, 150);
+
+    // calling back into the queue from one of its items would wait for itself
+    let nested = {
+        let same = actor.clone();
+        actor
+            .inner_call(async move |_| {
+                let err = same.inner_call_blocking(async move |inner| Ok(*inner.get())).unwrap_err();
+                Ok(err.downcast_ref::<AQueueError>().cloned())
+            })
+            .await?
+    };
+    assert_eq!(nested, Some(AQueueError::BlockingInAsync));
+
+    // would block a runtime worker
+    #[cfg(feature = "tokio")]
+    {
+        let err = actor.inner_call_blocking(async move |inner| Ok(*inner.get())).unwrap_err();
+        assert_eq!(err.downcast_ref::<AQueueError>(), Some(&AQueueError::BlockingInAsync));
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_runtime() -> Result<()> {
+    use aqueue::runtime::SleepFuture;
+    use aqueue::AQueueError;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // deadlines sleep on the queue's timer
+    let sleeps = Arc::new(AtomicUsize::new(0));
+    let queue = {
+        let sleeps = sleeps.clone();
+        AQueue::builder()
+            .timer(move |d| -> SleepFuture {
+                sleeps.fetch_add(1, Ordering::Relaxed);
+                Box::pin(sleep(d))
+            })
+            .build()
+    };
+    let err = queue
+        .run_timeout(
+            None,
+            Some(Duration::from_millis(10)),
+            async move |_| {
+                sleep(Duration::from_millis(200)).await;
+                Ok(())
+            },
+            (),
+        )
+        .await
+        .unwrap_err();
+    assert_eq!(err.downcast_ref::<AQueueError>(), Some(&AQueueError::ExecTimeout));
+    assert_eq!(sleeps.load(Ordering::Relaxed), 1);
+
+    #[cfg(feature = "rt-tokio")]
+    {
+        use aqueue::runtime::Tokio;
+
+        let queue = Arc::new(AQueue::builder().runtime(Tokio::current()).build());
+        let ran = Arc::new(AtomicUsize::new(0));
+        // nobody waits on it, the spawner runs it
+        queue.post(
+            async move |ran: Arc<AtomicUsize>| {
+                ran.fetch_add(1, Ordering::Relaxed);
+                Ok(())
+            },
+            ran.clone(),
+        )?;
+        sleep(Duration::from_millis(50)).await;
+        assert_eq!(ran.load(Ordering::Relaxed), 1);
+
+        let err = queue
+            .run_timeout(
+                None,
+                Some(Duration::from_millis(10)),
+                async move |_| {
+                    sleep(Duration::from_millis(200)).await;
+                    Ok(())
+                },
+                (),
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(err.downcast_ref::<AQueueError>(), Some(&AQueueError::ExecTimeout));
+
+        // a driver task on tokio
+        let queue = AQueue::builder().build_driven(Tokio::current());
+        assert_eq!(queue.run(async move |x| Ok(x + 1), 1).await?, 2);
+
+        // the handle is kept, posting from a plain thread spawns on the runtime all the same
+        let poster = queue.clone();
+        let ran2 = ran.clone();
+        std::thread::spawn(move || {
+            poster.post(
+                async move |ran: Arc<AtomicUsize>| {
+                    sleep(Duration::from_millis(1)).await;
+                    ran.fetch_add(1, Ordering::Relaxed);
+                    Ok(())
+                },
+                ran2,
+            )
+        })
+        .join()
+        .unwrap()?;
+        queue.drain().await;
+        assert_eq!(ran.load(Ordering::Relaxed), 2);
+    }
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_pool() -> Result<()> {
+    let queue = Arc::new(AQueue::builder().pool(4).build());
+
+    // one at a time, every call after the first reuses the same block
+    for i in 0..100u64 {
+        assert_eq!(queue.run(async move |x| Ok(x * 2), i).await?, i * 2);
+    }
+    let stats = queue.stats();
+    assert_eq!((stats.pool_hits, stats.pool_misses), (99, 1));
+
+    // at most as many blocks as calls in flight
+    let mut calls = Vec::new();
+    for i in 0..100u64 {
+        let queue = queue.clone();
+        calls.push(tokio::spawn(async move {
+            queue
+                .run(
+                    async move |x| {
+                        tokio::task::yield_now().await;
+                        Ok(x)
+                    },
+                    i,
+                )
+                .await
+        }));
+    }
+    for (i, call) in calls.into_iter().enumerate() {
+        assert_eq!(call.await??, i as u64);
+    }
+    let stats = queue.stats();
+    assert_eq!(stats.pool_hits + stats.pool_misses, 200);
+
+    // callers gone before their item runs, the blocks still come back
+    queue.pause();
+    for i in 0..4u64 {
+        let run = queue.run(async move |x| Ok(x), i);
+        assert!(tokio::time::timeout(Duration::from_millis(1), run).await.is_err());
+    }
+    queue.resume();
+    queue.drain().await;
+    let before = queue.stats().pool_misses;
+    for i in 0..4u64 {
+        queue.run(async move |x| Ok(x), i).await?;
+    }
+    assert_eq!(queue.stats().pool_misses, before);
+
+    // too big for a block, allocated on its own
+    let big = [7u8; 1024];
+    let before = queue.stats();
+    assert_eq!(queue.run(async move |big: [u8; 1024]| Ok(big[1023]), big).await?, 7);
+    let stats = queue.stats();
+    assert_eq!((stats.pool_hits, stats.pool_misses), (before.pool_hits, before.pool_misses));
+
+    // errors and panics come through the slot as before
+    let err = queue.run(async move |_| -> Result<()> { bail!("mine") }, ()).await.unwrap_err();
+    assert_eq!(err.to_string(), "mine");
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+#[cfg_attr(miri, ignore)]
+async fn test_handoff_race() -> Result<()> {
+    use aqueue::runtime::SpawnFuture;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn send_sync<T: Send + Sync>() {}
+    send_sync::<AQueue>();
+    send_sync::<Actor<u64>>();
+
+    // posts are only run by the spawned task: posting right after the last one ran lands
+    // while that task is on its way out, and nobody else would run it if the task missed it
+    let queue = Arc::new(
+        AQueue::builder()
+            .spawner(|fut: SpawnFuture| {
+                tokio::spawn(fut);
+            })
+            .build(),
+    );
+    let count = Arc::new(AtomicUsize::new(0));
+    for i in 1..=20000 {
+        queue.post(
+            async move |count: Arc<AtomicUsize>| {
+                count.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            },
+            count.clone(),
+        )?;
+        let started = Instant::now();
+        while count.load(Ordering::SeqCst) < i {
+            assert!(started.elapsed() < Duration::from_secs(5), "post {} was never run", i);
+            std::hint::spin_loop();
+        }
+    }
+
+    // the same race against resume
+    queue.pause();
+    queue.post(
+        async move |count: Arc<AtomicUsize>| {
+            count.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        },
+        count.clone(),
+    )?;
+    queue.resume();
+    queue.drain().await;
+    assert_eq!(count.load(Ordering::SeqCst), 20001);
+    Ok(())
+}
+
+/// The suites below run once per runtime backend: on tokio always (`runtime::Tokio` with `rt-tokio`,
+/// a stand-in over the same calls otherwise), on async-std and smol with `rt-async-std` and `rt-smol`.
+/// They only reach the runtime through the backend they are given, items sleep on `futures-timer`.
+mod backend {
+    use super::*;
+    use aqueue::{Spawner, Timer};
+    use std::future::{poll_fn, Future};
+    use std::pin::pin;
+    use std::task::Poll;
+
+    /// One `#[test]` per backend for each suite, the attributes go on each of them
+    macro_rules! backend_tests {
+        ($($(#[$attr:meta])* $suite:ident),* $(,)?) => {$(
+            mod $suite {
+                #[test]
+                $(#[$attr])*
+                fn tokio() {
+                    let rt = ::tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+                    rt.block_on(async { super::$suite(super::tokio_rt()).await }).unwrap();
+                }
+
+                #[cfg(all(feature = "rt-async-std", not(miri)))]
+                #[test]
+                $(#[$attr])*
+                fn async_std() {
+                    ::async_std::task::block_on(super::$suite(aqueue::runtime::AsyncStd)).unwrap();
+                }
+
+                #[cfg(all(feature = "rt-smol", not(miri)))]
+                #[test]
+                $(#[$attr])*
+                fn smol() {
+                    ::smol::block_on(super::$suite(aqueue::runtime::Smol)).unwrap();
+                }
+            }
+        )*};
+    }
+
+    backend_tests! {
+        #[cfg_attr(miri, ignore)]
+        test_bounded,
+        test_try_run,
+        #[cfg_attr(miri, ignore)]
+        test_priority,
+        #[cfg_attr(miri, ignore)]
+        test_timeout,
+        test_cancel,
+        test_panic,
+        #[cfg_attr(miri, ignore)]
+        test_dropped_receiver,
+        #[cfg_attr(miri, ignore)]
+        test_post,
+        #[cfg_attr(miri, ignore)]
+        test_batch,
+        test_close,
+        test_pause,
+        #[cfg_attr(miri, ignore)]
+        test_driver,
+        #[cfg_attr(miri, ignore)]
+        test_drain_handover,
+        #[cfg_attr(miri, ignore)]
+        test_backend,
+        test_ref_run,
+    }
+
+    /// What a suite gets the runtime through
+    trait Rt: Spawner + Timer + Clone + Send + Sync + 'static {}
+
+    impl<R: Spawner + Timer + Clone + Send + Sync + 'static> Rt for R {}
+
+    #[cfg(feature = "rt-tokio")]
+    fn tokio_rt() -> impl Rt {
+        aqueue::runtime::Tokio::current()
+    }
+
+    #[cfg(not(feature = "rt-tokio"))]
+    fn tokio_rt() -> impl Rt {
+        use aqueue::runtime::{SleepFuture, SpawnFuture};
+
+        #[derive(Clone)]
+        struct Tokio(tokio::runtime::Handle);
+
+        impl Spawner for Tokio {
+            fn spawn(&self, fut: SpawnFuture) {
+                self.0.spawn(fut);
+            }
+        }
+
+        impl Timer for Tokio {
+            fn sleep(&self, duration: Duration) -> SleepFuture {
+                let _enter = self.0.enter();
+                Box::pin(tokio::time::sleep(duration))
+            }
+        }
+
+        Tokio(tokio::runtime::Handle::current())
+    }
+
+    /// Run `fut` as a task of the backend, its output comes back through the returned future
+    fn spawn<T: Send + Sync + 'static>(rt: &impl Rt, fut: impl Future<Output = T> + Send + 'static) -> impl Future<Output = Result<T>> {
+        let (mut tx, rx) = async_oneshot::oneshot();
+        rt.spawn(Box::pin(async move {
+            let _ = tx.send(fut.await);
+        }));
+        async move { rx.await.map_err(|_| anyhow!("task dropped")) }
+    }
+
+    #[derive(Debug)]
+    struct Elapsed;
+
+    /// Wait for `fut` at most `limit`, on the backend's timer
+    async fn timeout<T>(rt: &impl Rt, limit: Duration, fut: impl Future<Output = T>) -> Result<T, Elapsed> {
+        let mut fut = pin!(fut);
+        let mut sleep = rt.sleep(limit);
+        poll_fn(|cx| {
+            if let Poll::Ready(v) = fut.as_mut().poll(cx) {
+                return Poll::Ready(Ok(v));
+            }
+            sleep.as_mut().poll(cx).map(|()| Err(Elapsed))
+        })
+        .await
+    }
+
+    async fn sleep(duration: Duration) {
+        futures_timer::Delay::new(duration).await
+    }
+
+    async fn test_bounded(rt: impl Rt) -> Result<(), Box<dyn Error>> {
+        let queue = Arc::new(AQueue::with_capacity(4));
+        assert_eq!(queue.capacity(), Some(4));
+
+        let mut vec = vec![];
+        for i in 0..100u64 {
+            let a_queue = queue.clone();
+            vec.push(spawn(&rt, async move {
+                let mut sum = 0;
+                for j in 0..1000u64 {
+                    sum += a_queue
+                        .run(
+                            async move |x| {
+                                if x % 100 == 0 {
+                                    sleep(Duration::from_micros(10)).await;
+                                }
+                                Ok(x)
+                            },
+                            i * 1000 + j,
+                        )
+                        .await
+                        .unwrap();
+                }
+                sum
+            }));
+        }
+
+        let mut total = 0;
+        for j in vec {
+            total += j.await?;
+        }
+        assert_eq!(total, (0..100000u64).sum::<u64>());
+
+        let actor = Actor::with_capacity(0u64, 1);
+        let actor = Arc::new(actor);
+        let mut vec = vec![];
+        for _ in 0..10 {
+            let a_actor = actor.clone();
+            vec.push(spawn(&rt, async move {
+                for _ in 0..100 {
+                    a_actor
+                        .inner_call(async move |inner| {
+                            *inner.get_mut() += 1;
+                            Ok(())
+                        })
+                        .await
+                        .unwrap();
+                }
+            }));
+        }
+        for j in vec {
+            j.await?;
+        }
+        assert_eq!(actor.inner_call(async move |inner| Ok(*inner.get())).await?, 1000);
+        Ok(())
+    }
+
+    async fn test_try_run(rt: impl Rt) -> Result<(), Box<dyn Error>> {
+        let actor = Arc::new(Actor::with_capacity(0u64, 1));
+
+        let a_actor = actor.clone();
+        let a = spawn(&rt, async move {
+            a_actor
+                .inner_call(async move |inner| {
+                    sleep(Duration::from_millis(200)).await;
+                    *inner.get_mut() += 1;
+                    Ok(())
+                })
+                .await
+        });
+        sleep(Duration::from_millis(20)).await;
+
+        let b_actor = actor.clone();
+        let b = spawn(&rt, async move {
+            b_actor
+                .inner_call(async move |inner| {
+                    *inner.get_mut() += 1;
+                    Ok(())
+                })
+                .await
+        });
+        sleep(Duration::from_millis(20)).await;
+
+        let err = actor
+            .try_inner_call(async move |inner| {
+                *inner.get_mut() += 1;
+                Ok(())
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(err.downcast_ref::<aqueue::AQueueError>(), Some(&aqueue::AQueueError::Full));
+
+        a.await??;
+        b.await??;
+
+        let v = actor.try_inner_call(async move |inner| Ok(*inner.get())).await?;
+        assert_eq!(v, 2);
+        Ok(())
+    }
+
+    async fn test_priority(rt: impl Rt) -> Result<(), Box<dyn Error>> {
+        use aqueue::{Priority, Scheduling};
+
+        async fn run_order(rt: &impl Rt, actor: Arc<Actor<Vec<String>>>, calls: Vec<(Priority, &'static str)>) -> Result<Vec<String>> {
+            let a_actor = actor.clone();
+            let blocker = spawn(rt, async move {
+                a_actor
+                    .inner_call(async move |_| {
+                        sleep(Duration::from_millis(200)).await;
+                        Ok(())
+                    })
+                    .await
+            });
+            sleep(Duration::from_millis(10)).await;
+
+            let mut vec = vec![];
+            for (priority, name) in calls {
+                let a_actor = actor.clone();
+                vec.push(spawn(rt, async move {
+                    a_actor
+                        .inner_call_with_priority(priority, async move |inner| {
+                            inner.get_mut().push(name.to_string());
+                            Ok(())
+                        })
+                        .await
+                }));
+                sleep(Duration::from_millis(1)).await;
+            }
+
+            blocker.await??;
+            for j in vec {
+                j.await??;
+            }
+            actor.inner_call(async move |inner| Ok(std::mem::take(inner.get_mut()))).await
+        }
+
+        let actor = Arc::new(Actor::new(Vec::new()));
+        let order = run_order(
+            &rt,
+            actor,
+            vec![(Priority::Low, "l1"), (Priority::Normal, "n1"), (Priority::Low, "l2"), (Priority::Normal, "n2"), (Priority::High, "h1")],
+        )
+        .await?;
+        assert_eq!(order, vec!["h1", "n1", "n2", "l1", "l2"]);
+
+        let actor = Arc::new(Actor::with_queue(Vec::new(), AQueue::builder().scheduling(Scheduling::Aging(2)).build()));
+        let mut calls = vec![(Priority::Low, "l1"), (Priority::Low, "l2"), (Priority::Low, "l3")];
+        calls.extend(["h1", "h2", "h3", "h4", "h5", "h6"].iter().map(|name| (Priority::High, *name)));
+        let order = run_order(&rt, actor, calls).await?;
+        assert_eq!(order, vec!["h1", "h2", "l1", "h3", "h4", "l2", "h5", "h6", "l3"]);
+
+        // a lane due after 0 skips would always be due, that would just invert the priorities
+        assert!(std::panic::catch_unwind(|| AQueue::builder().scheduling(Scheduling::Aging(0))).is_err());
+        Ok(())
+    }
+
+    async fn test_timeout(rt: impl Rt) -> Result<(), Box<dyn Error>> {
+        use aqueue::AQueueError;
+
+        // the deadlines sleep on the backend's timer
+        let actor = Arc::new(Actor::with_queue(0u64, AQueue::builder().timer(rt.clone()).build()));
+
+        let a_actor = actor.clone();
+        let blocker = spawn(&rt, async move {
+            a_actor
+                .inner_call(async move |_| {
+                    sleep(Duration::from_millis(300)).await;
+                    Ok(())
+                })
+                .await
+        });
+        sleep(Duration::from_millis(10)).await;
+
+        let start = Instant::now();
+        let err = actor
+            .inner_call_timeout(Some(Duration::from_millis(50)), None, async move |inner| {
+                *inner.get_mut() += 1;
+                Ok(())
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(err.downcast_ref::<AQueueError>(), Some(&AQueueError::WaitTimeout));
+        assert!(start.elapsed() < Duration::from_millis(250));
+        blocker.await??;
+        // the timed out item was dropped, never run
+        assert_eq!(actor.inner_call(async move |inner| Ok(*inner.get())).await?, 0);
+
+        let start = Instant::now();
+        let err = actor
+            .inner_call_timeout(None, Some(Duration::from_millis(50)), async move |inner| {
+                sleep(Duration::from_secs(1)).await;
+                *inner.get_mut() += 1;
+                Ok(())
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(err.downcast_ref::<AQueueError>(), Some(&AQueueError::ExecTimeout));
+        assert!(start.elapsed() < Duration::from_millis(500));
+        assert_eq!(actor.inner_call(async move |inner| Ok(*inner.get())).await?, 0);
+
+        let v = actor
+            .inner_call_timeout(Some(Duration::from_millis(100)), Some(Duration::from_millis(100)), async move |inner| {
+                *inner.get_mut() += 1;
+                Ok(*inner.get())
+            })
+            .await?;
+        assert_eq!(v, 1);
+
+        // the caller runs the queue itself, stuck behind a slow posted item: it still gives up in time,
+        // and the posted item is left for the next one to finish
+        let queue = AQueue::builder().timer(rt.clone()).build_shared();
+        let done = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        queue.post(
+            async move |done: Arc<std::sync::atomic::AtomicBool>| {
+                sleep(Duration::from_millis(300)).await;
+                done.store(true, std::sync::atomic::Ordering::SeqCst);
+                Ok(())
+            },
+            done.clone(),
+        )?;
+        let start = Instant::now();
+        let err = queue
+            .run_timeout(Some(Duration::from_millis(20)), None, async move |_| -> Result<()> { panic!("never started") }, ())
+            .await
+            .unwrap_err();
+        assert_eq!(err.downcast_ref::<AQueueError>(), Some(&AQueueError::WaitTimeout));
+        assert!(start.elapsed() < Duration::from_millis(200));
+        assert!(!done.load(std::sync::atomic::Ordering::SeqCst));
+        queue.drain().await;
+        assert!(done.load(std::sync::atomic::Ordering::SeqCst));
+        assert_eq!(queue.pending(), 0);
+        Ok(())
+    }
+
+    async fn test_cancel(rt: impl Rt) -> Result<(), Box<dyn Error>> {
+        let actor = Arc::new(Actor::new(0u64));
+
+        // a caller dropped while its item waits: the item never runs
+        let a_actor = actor.clone();
+        let blocker = spawn(&rt, async move {
+            a_actor
+                .inner_call(async move |_| {
+                    sleep(Duration::from_millis(100)).await;
+                    Ok(())
+                })
+                .await
+        });
+        sleep(Duration::from_millis(10)).await;
+        let r = timeout(
+            &rt,
+            Duration::from_millis(20),
+            actor.inner_call(async move |inner| {
+                *inner.get_mut() += 1;
+                Ok(())
+            }),
+        )
+        .await;
+        assert!(r.is_err());
+        blocker.await??;
+        assert_eq!(actor.inner_call(async move |inner| Ok(*inner.get())).await?, 0);
+
+        // a caller dropped while its item runs: the item sees it through the token
+        let a_actor = actor.clone();
+        let blocker = spawn(&rt, async move {
+            a_actor
+                .inner_call(async move |_| {
+                    sleep(Duration::from_millis(50)).await;
+                    Ok(())
+                })
+                .await
+        });
+        sleep(Duration::from_millis(10)).await;
+        let r = timeout(
+            &rt,
+            Duration::from_millis(100),
+            actor.inner_call_cancellable(async move |inner, token| {
+                assert!(!token.is_cancelled());
+                token.cancelled().await;
+                *inner.get_mut() += 10;
+                Ok(())
+            }),
+        )
+        .await;
+        assert!(r.is_err());
+        blocker.await??;
+        assert_eq!(actor.inner_call(async move |inner| Ok(*inner.get())).await?, 10);
+
+        // the caller running the queue is dropped mid-item: a waiting caller takes over,
+        // finishing the item in flight first
+        let b_actor = actor.clone();
+        let b = spawn(&rt, async move {
+            sleep(Duration::from_millis(10)).await;
+            b_actor
+                .inner_call(async move |inner| {
+                    *inner.get_mut() += 1;
+                    Ok(*inner.get())
+                })
+                .await
+        });
+        let r = timeout(
+            &rt,
+            Duration::from_millis(50),
+            actor.inner_call(async move |inner| {
+                sleep(Duration::from_millis(100)).await;
+                *inner.get_mut() += 100;
+                Ok(())
+            }),
+        )
+        .await;
+        assert!(r.is_err());
+        assert_eq!(b.await??, 111);
+        Ok(())
+    }
+
+    async fn test_panic(rt: impl Rt) -> Result<(), Box<dyn Error>> {
+        use aqueue::AQueueError;
+
+        let actor = Arc::new(Actor::new(0u64));
+        let mut vec = vec![];
+        for i in 0..10u64 {
+            let a_actor = actor.clone();
+            vec.push(spawn(&rt, async move {
+                a_actor
+                    .inner_call(async move |inner| {
+                        sleep(Duration::from_millis(1)).await;
+                        if i == 3 {
+                            panic!("boom {}", i);
+                        }
+                        *inner.get_mut() += 1;
+                        Ok(i)
+                    })
+                    .await
+            }));
+        }
+
+        for (i, j) in vec.into_iter().enumerate() {
+            match j.await? {
+                Ok(v) => assert_eq!(v, i as u64),
+                Err(err) => {
+                    assert_eq!(i, 3);
+                    assert_eq!(err.downcast_ref::<AQueueError>(), Some(&AQueueError::Panicked("boom 3".to_string())));
+                }
+            }
+        }
+
+        assert!(actor.is_poisoned());
+        assert_eq!(actor.inner_call(async move |inner| Ok(*inner.get())).await?, 9);
+        actor.clear_poison();
+        assert!(!actor.is_poisoned());
+        Ok(())
+    }
+
+    async fn test_dropped_receiver(rt: impl Rt) -> Result<(), Box<dyn Error>> {
+        let actor = Arc::new(Actor::new(0u64));
+
+        // a runs the queue, b's caller goes away while b runs, c waits behind b
+        let a_actor = actor.clone();
+        let a = spawn(&rt, async move {
+            a_actor
+                .inner_call(async move |_| {
+                    sleep(Duration::from_millis(20)).await;
+                    Ok(1)
+                })
+                .await
+        });
+        sleep(Duration::from_millis(5)).await;
+        let b_actor = actor.clone();
+        let b_rt = rt.clone();
+        let b = spawn(&rt, async move {
+            timeout(
+                &b_rt,
+                Duration::from_millis(60),
+                b_actor.inner_call(async move |_| {
+                    sleep(Duration::from_millis(50)).await;
+                    Ok(2)
+                }),
+            )
+            .await
+        });
+        sleep(Duration::from_millis(5)).await;
+        let c_actor = actor.clone();
+        let c = spawn(&rt, async move { c_actor.inner_call(async move |_| Ok(3)).await });
+
+        assert_eq!(a.await??, 1);
+        assert!(b.await?.is_err());
+        assert_eq!(c.await??, 3);
+
+        // a burst where every third caller gives up half way
+        let mut vec = vec![];
+        for i in 0..300u64 {
+            let a_actor = actor.clone();
+            let a_rt = rt.clone();
+            vec.push(spawn(&rt, async move {
+                let call = a_actor.inner_call(async move |inner| {
+                    if i % 10 == 0 {
+                        sleep(Duration::from_millis(1)).await;
+                    }
+                    *inner.get_mut() += 1;
+                    Ok(i)
+                });
+                if i % 3 == 0 {
+                    let _ = timeout(&a_rt, Duration::from_millis(2), call).await;
+                    None
+                } else {
+                    Some(call.await)
+                }
+            }));
+        }
+        for (i, j) in vec.into_iter().enumerate() {
+            if let Some(r) = j.await? {
+                assert_eq!(r?, i as u64);
+            }
+        }
+        let v = actor.inner_call(async move |inner| Ok(*inner.get())).await?;
+        assert!((200..=300).contains(&v));
+        Ok(())
+    }
+
+    async fn test_post(rt: impl Rt) -> Result<(), Box<dyn Error>> {
+        use std::sync::Mutex;
+
+        let errors = Arc::new(Mutex::new(Vec::new()));
+        let sink = errors.clone();
+        let queue = AQueue::builder()
+            .spawner(rt.clone())
+            .error_sink(move |err| sink.lock().unwrap().push(err.to_string()))
+            .build();
+        let actor = Actor::with_queue(0u64, queue);
+
+        for i in 0..100u64 {
+            actor.post(async move |inner| {
+                if i == 50 {
+                    bail!("bad {}", i)
+                }
+                *inner.get_mut() += 1;
+                Ok(())
+            })?;
+        }
+
+        // nobody awaits the posts, the spawned task runs them
+        sleep(Duration::from_millis(100)).await;
+        assert_eq!(unsafe { *actor.deref_inner() }, 99);
+        assert_eq!(*errors.lock().unwrap(), vec!["bad 50".to_string()]);
+
+        // posted while a caller runs the queue, which is then dropped: a task picks the post up
+        let queue = AQueue::builder()
+            .spawner(rt.clone())
+            .build_shared();
+        let posted = Arc::new(Mutex::new(false));
+        let (r, ()) = tokio::join!(
+            timeout(
+                &rt,
+                Duration::from_millis(20),
+                queue.run(
+                    async move |_| {
+                        sleep(Duration::from_millis(50)).await;
+                        Ok(())
+                    },
+                    (),
+                )
+            ),
+            async {
+                sleep(Duration::from_millis(5)).await;
+                queue
+                    .post(
+                        async move |posted: Arc<Mutex<bool>>| {
+                            *posted.lock().unwrap() = true;
+                            Ok(())
+                        },
+                        posted.clone(),
+                    )
+                    .unwrap();
+            }
+        );
+        assert!(r.is_err());
+        sleep(Duration::from_millis(100)).await;
+        assert!(*posted.lock().unwrap());
+        assert_eq!(queue.pending(), 0);
+
+        // without a spawner, the next caller runs them
+        let queue = Arc::new(AQueue::new());
+        let counter = Arc::new(Mutex::new(0));
+        for _ in 0..10 {
+            queue.post(
+                async move |counter: Arc<Mutex<i32>>| {
+                    *counter.lock().unwrap() += 1;
+                    Ok(())
+                },
+                counter.clone(),
+            )?;
+        }
+        assert_eq!(*counter.lock().unwrap(), 0);
+        let v = queue.run(async move |counter: Arc<Mutex<i32>>| Ok(*counter.lock().unwrap()), counter.clone()).await?;
+        assert_eq!(v, 10);
+        Ok(())
+    }
+
+    async fn test_batch(rt: impl Rt) -> Result<(), Box<dyn Error>> {
+        let actor = Arc::new(Actor::with_capacity(Vec::new(), 2));
+
+        let a_actor = actor.clone();
+        let singles = spawn(&rt, async move {
+            for i in 0..200 {
+                a_actor
+                    .inner_call(async move |inner| {
+                        inner.get_mut().push(format!("s{}", i));
+                        Ok(())
+                    })
+                    .await
+                    .unwrap();
+            }
+        });
+
+        sleep(Duration::from_millis(1)).await;
+        let results = actor
+            .inner_call_batch((0..1000).map(|i| {
+                async move |inner: Arc<aqueue::actor::InnerStore<Vec<String>>>| {
+                    if i == 500 {
+                        bail!("bad {}", i)
+                    }
+                    inner.get_mut().push(format!("b{}", i));
+                    Ok(i)
+                }
+            }))
+            .await?;
+        singles.await?;
+
+        assert_eq!(results.len(), 1000);
+        for (i, r) in results.into_iter().enumerate() {
+            match r {
+                Ok(v) => assert_eq!(v, i),
+                Err(err) => {
+                    assert_eq!(i, 500);
+                    assert_eq!(err.to_string(), "bad 500");
+                }
+            }
+        }
+
+        let log = actor.inner_call(async move |inner| Ok(inner.get().clone())).await?;
+        assert_eq!(log.len(), 1199);
+        let first = log.iter().position(|x| x.starts_with('b')).unwrap();
+        assert!(log[first..first + 999].iter().all(|x| x.starts_with('b')));
+        Ok(())
+    }
+
+    async fn test_close(rt: impl Rt) -> Result<()> {
+        let actor = Arc::new(Actor::new(0u64));
+
+        // one call holds the queue, the rest pile up behind it
+        let mut calls = Vec::new();
+        for _ in 0..10 {
+            let actor = actor.clone();
+            calls.push(spawn(&rt, async move {
+                actor
+                    .inner_call(async move |inner| {
+                        sleep(Duration::from_millis(10)).await;
+                        *inner.get_mut() += 1;
+                        Ok(())
+                    })
+                    .await
+            }));
+        }
+        sleep(Duration::from_millis(5)).await;
+
+        actor.close();
+        assert!(actor.is_closed());
+        let err = actor.inner_call(async move |inner| Ok(*inner.get())).await.unwrap_err();
+        assert_eq!(err.downcast_ref::<aqueue::AQueueError>(), Some(&aqueue::AQueueError::Closed));
+        let err = actor.post(async move |inner| Ok(*inner.get())).unwrap_err();
+        assert_eq!(err.downcast_ref::<aqueue::AQueueError>(), Some(&aqueue::AQueueError::Closed));
+
+        // accepted calls still complete
+        actor.drain().await;
+        assert_eq!(*unsafe { actor.deref_inner() }, 10);
+        for call in calls {
+            call.await??;
+        }
+
+        let actor = Arc::try_unwrap(actor).ok().unwrap();
+        assert_eq!(actor.shutdown().await?, 10);
+
+        // posted items are run by drain when nobody else does
+        let queue = Arc::new(AQueue::new());
+        let count = Arc::new(std::sync::atomic::AtomicU32::new(0));
+        for _ in 0..100 {
+            queue.post(
+                async move |count: Arc<std::sync::atomic::AtomicU32>| {
+                    count.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                    Ok(())
+                },
+                count.clone(),
+            )?;
+        }
+        queue.close();
+        queue.drain().await;
+        assert_eq!(count.load(std::sync::atomic::Ordering::Relaxed), 100);
+        Ok(())
+    }
+
+    async fn test_pause(rt: impl Rt) -> Result<()> {
+        let actor = Arc::new(Actor::with_capacity(Vec::new(), 4));
+        actor.pause();
+        assert!(actor.is_paused());
+
+        let mut calls = Vec::new();
+        for i in 0..6 {
+            let actor = actor.clone();
+            calls.push(spawn(&rt, async move {
+                actor
+                    .inner_call(async move |inner| {
+                        inner.get_mut().push(i);
+                        Ok(())
+                    })
+                    .await
+            }));
+            sleep(Duration::from_millis(2)).await;
+        }
+        sleep(Duration::from_millis(20)).await;
+
+        // held, not run, and the bounded queue still pushes back
+        assert_eq!(actor.pending(), 4);
+        assert!(unsafe { actor.deref_inner() }.is_empty());
+        let err = actor.try_inner_call(async move |_| Ok(())).await.unwrap_err();
+        assert_eq!(err.downcast_ref::<aqueue::AQueueError>(), Some(&aqueue::AQueueError::Full));
+
+        actor.resume();
+        for call in calls {
+            call.await??;
+        }
+        assert_eq!(actor.pending(), 0);
+        assert_eq!(*unsafe { actor.deref_inner() }, vec![0, 1, 2, 3, 4, 5]);
+
+        // posted items held by a pause are picked up by drain once resumed
+        let queue = Arc::new(AQueue::new());
+        let count = Arc::new(std::sync::atomic::AtomicU32::new(0));
+        queue.pause();
+        for _ in 0..10 {
+            queue.post(
+                async move |count: Arc<std::sync::atomic::AtomicU32>| {
+                    count.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                    Ok(())
+                },
+                count.clone(),
+            )?;
+        }
+        let drain = {
+            let queue = queue.clone();
+            spawn(&rt, async move { queue.drain().await })
+        };
+        sleep(Duration::from_millis(10)).await;
+        assert_eq!(count.load(std::sync::atomic::Ordering::Relaxed), 0);
+        assert_eq!(queue.pending(), 10);
+        queue.resume();
+        drain.await?;
+        assert_eq!(count.load(std::sync::atomic::Ordering::Relaxed), 10);
+
+        // with a spawner, the posts held by a pause are run once resumed, nobody calling
+        let actor = Actor::with_queue(
+            0u32,
+            AQueue::builder().spawner(rt.clone()).build(),
+        );
+        actor.pause();
+        for _ in 0..10 {
+            actor.post(async move |inner| {
+                *inner.get_mut() += 1;
+                Ok(())
+            })?;
+        }
+        sleep(Duration::from_millis(10)).await;
+        assert_eq!(actor.pending(), 10);
+        actor.resume();
+        sleep(Duration::from_millis(200)).await;
+        assert_eq!(actor.pending(), 0);
+        assert_eq!(*unsafe { actor.deref_inner() }, 10);
+
+        // a queue held by value resumes just the same
+        let queue = AQueue::new();
+        queue.pause();
+        let (r, ()) = tokio::join!(queue.run(async move |x| Ok(x + 1), 1), async {
+            sleep(Duration::from_millis(5)).await;
+            assert_eq!(queue.pending(), 1);
+            queue.resume();
+        });
+        assert_eq!(r?, 2);
+        Ok(())
+    }
+
+    async fn test_driver(rt: impl Rt) -> Result<()> {
+        let queue = AQueue::builder().build_driven(rt.clone());
+
+        // the first caller's result must not wait for the slow item queued behind it
+        let first = {
+            let queue = queue.clone();
+            spawn(&rt, async move {
+                let start = Instant::now();
+                queue
+                    .run(
+                        async move |_| {
+                            sleep(Duration::from_millis(20)).await;
+                            Ok(())
+                        },
+                        (),
+                    )
+                    .await?;
+                Ok::<_, anyhow::Error>(start.elapsed())
+            })
+        };
+        sleep(Duration::from_millis(5)).await;
+        let slow = {
+            let queue = queue.clone();
+            spawn(&rt, async move {
+                queue
+                    .run(
+                        async move |_| {
+                            sleep(Duration::from_millis(300)).await;
+                            Ok(2)
+                        },
+                        (),
+                    )
+                    .await
+            })
+        };
+        assert!(first.await?? < Duration::from_millis(200));
+        assert_eq!(slow.await??, 2);
+
+        // an actor on a driven queue
+        let actor = Actor::with_queue(
+            0u64,
+            AQueue::builder().build_driven(rt.clone()),
+        );
+        for _ in 0..1000 {
+            actor
+                .inner_call(async move |inner| {
+                    *inner.get_mut() += 1;
+                    Ok(())
+                })
+                .await?;
+        }
+        assert_eq!(actor.shutdown().await?, 1000);
+        Ok(())
+    }
+
+    async fn test_drain_handover(rt: impl Rt) -> Result<()> {
+        use std::sync::atomic::{AtomicU32, Ordering};
+
+        // callers still all get their result when the running side hands over
+        for queue in [
+            AQueue::builder().drain_budget(3).build_shared(),
+            AQueue::builder().drain_time(Duration::from_millis(1)).build_shared(),
+        ] {
+            let mut calls = Vec::new();
+            for i in 0..50u32 {
+                let queue = queue.clone();
+                calls.push(spawn(&rt, async move { queue.run(async move |x| Ok(x * 2), i).await }));
+            }
+            for (i, call) in calls.into_iter().enumerate() {
+                assert_eq!(call.await??, i as u32 * 2);
+            }
+        }
+
+        // posts nobody waits on still all run on the spawner, the budget spent over and over
+        let queue = AQueue::builder().drain_budget(3).spawner(rt.clone()).build_shared();
+        let count = Arc::new(AtomicU32::new(0));
+        for _ in 0..20 {
+            queue.post(
+                async move |count: Arc<AtomicU32>| {
+                    count.fetch_add(1, Ordering::Relaxed);
+                    Ok(())
+                },
+                count.clone(),
+            )?;
+        }
+        let started = Instant::now();
+        while count.load(Ordering::Relaxed) < 20 {
+            assert!(started.elapsed() < Duration::from_secs(5), "posts stranded");
+            sleep(Duration::from_millis(1)).await;
+        }
+        Ok(())
+    }
+
+    // what the queue needs from a runtime backend: posts, deadlines, and a driven queue shared with a plain thread
+    async fn test_backend(rt: impl Rt) -> Result<()> {
+        use aqueue::AQueueError;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        // posts nobody waits on run on the spawner
+        let queue = Arc::new(AQueue::builder().runtime(rt.clone()).build());
+        let ran = Arc::new(AtomicUsize::new(0));
+        queue.post(
+            async move |ran: Arc<AtomicUsize>| {
+                ran.fetch_add(1, Ordering::Relaxed);
+                Ok(())
+            },
+            ran.clone(),
+        )?;
+        let started = Instant::now();
+        while ran.load(Ordering::Relaxed) == 0 {
+            assert!(started.elapsed() < Duration::from_secs(5), "post never ran");
+            rt.sleep(Duration::from_millis(1)).await;
+        }
+
+        // deadlines fire on the backend's timer
+        let timer = rt.clone();
+        let err = queue
+            .run_timeout(
+                None,
+                Some(Duration::from_millis(10)),
+                async move |_| {
+                    timer.sleep(Duration::from_secs(1)).await;
+                    Ok(())
+                },
+                (),
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(err.downcast_ref::<AQueueError>(), Some(&AQueueError::ExecTimeout));
+
+        // an actor on a driven queue, called from async code and from a plain thread at once
+        let actor = Arc::new(Actor::with_queue(Vec::new(), AQueue::builder().build_driven(rt.clone())));
+        let blocking = {
+            let actor = actor.clone();
+            let timer = rt.clone();
+            std::thread::spawn(move || {
+                for i in 0..20 {
+                    let timer = timer.clone();
+                    actor.inner_call_blocking(async move |inner| {
+                        timer.sleep(Duration::from_millis(1)).await;
+                        inner.get_mut().push(i);
+                        Ok(())
+                    })?;
+                }
+                Ok::<_, anyhow::Error>(())
+            })
+        };
+        for i in 100..120 {
+            let timer = rt.clone();
+            actor
+                .inner_call(async move |inner| {
+                    timer.sleep(Duration::from_millis(1)).await;
+                    inner.get_mut().push(i);
+                    Ok(())
+                })
+                .await?;
+        }
+        while !blocking.is_finished() {
+            rt.sleep(Duration::from_millis(1)).await;
+        }
+        blocking.join().unwrap()?;
+        let mut seen = actor.inner_call(async move |inner| Ok(std::mem::take(inner.get_mut()))).await?;
+        seen.sort_unstable();
+        assert_eq!(seen, (0..20).chain(100..120).collect::<Vec<_>>());
+        Ok(())
+    }
+
+    async fn test_ref_run(rt: impl Rt) -> Result<()> {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::Mutex;
+
+        let queue = Arc::new(AQueue::new());
+        let busy = Arc::new(AtomicBool::new(false));
+        let order = Arc::new(Mutex::new(Vec::new()));
+
+        // queued before the borrowing call, they run before it and never alongside it;
+        // posted, so each is in the lanes before the next push, and with no spawner the borrowing call runs them
+        for i in 0..4 {
+            let busy = busy.clone();
+            let order = order.clone();
+            queue.post(
+                async move |_| {
+                    assert!(!busy.swap(true, Ordering::SeqCst));
+                    sleep(Duration::from_millis(5)).await;
+                    order.lock().unwrap().push(i);
+                    busy.store(false, Ordering::SeqCst);
+                    Ok(())
+                },
+                (),
+            )?;
+        }
+
+        let mut local = vec![1, 2, 3];
+        let sum = queue
+            .ref_run(
+                async |_| {
+                    assert!(!busy.swap(true, Ordering::SeqCst));
+                    sleep(Duration::from_millis(5)).await;
+                    local.push(4);
+                    busy.store(false, Ordering::SeqCst);
+                    Ok(local.iter().sum::<i32>())
+                },
+                (),
+            )
+            .await?;
+        assert_eq!(sum, 10);
+        assert_eq!(local, vec![1, 2, 3, 4]);
+        assert_eq!(*order.lock().unwrap(), vec![0, 1, 2, 3]);
+
+        // dropped before its turn: the call never runs and the queue goes on
+        let mut touched = false;
+        queue.pause();
+        let r = timeout(
+            &rt,
+            Duration::from_millis(5),
+            queue.ref_run(
+                async |_| {
+                    touched = true;
+                    Ok(())
+                },
+                (),
+            ),
+        )
+        .await;
+        assert!(r.is_err());
+        queue.resume();
+        assert_eq!(queue.run(async move |x| Ok(x + 1), 1).await?, 2);
+        assert!(!touched);
+
+        // dropped mid-call: the queue goes on too
+        let r = timeout(&rt, Duration::from_millis(5), queue.ref_run(async |_| -> Result<()> { std::future::pending().await }, ())).await;
+        assert!(r.is_err());
+        assert_eq!(queue.run(async move |x| Ok(x + 1), 2).await?, 3);
+
+        // panicked mid-call: the caller gets `Panicked` and the queue goes on
+        let r = queue
+            .ref_run(
+                async |_| -> Result<()> {
+                    sleep(Duration::from_millis(1)).await;
+                    panic!("boom");
+                },
+                (),
+            )
+            .await;
+        assert_eq!(r.unwrap_err().downcast_ref::<aqueue::AQueueError>(), Some(&aqueue::AQueueError::Panicked("boom".to_string())));
+        assert_eq!(queue.run(async move |x| Ok(x + 1), 3).await?, 4);
+
+        // held by another caller the whole time, the turn comes to us through them
+        let other = queue.clone();
+        let slow = spawn(&rt, async move {
+            other
+                .run(
+                    async move |_| {
+                        sleep(Duration::from_millis(20)).await;
+                        Ok(())
+                    },
+                    (),
+                )
+                .await
+        });
+        sleep(Duration::from_millis(5)).await;
+        let name = String::from("borrowed");
+        assert_eq!(queue.ref_run(async |_| Ok(name.len()), ()).await?, 8);
+        slow.await??;
+        queue.drain().await;
+        Ok(())
+    }
+}